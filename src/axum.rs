@@ -0,0 +1,292 @@
+use std::convert::Infallible;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ::axum::extract::{ConnectInfo, FromRequestParts};
+use ::axum::http::request::Parts;
+use ::axum::http::{HeaderName, HeaderValue, StatusCode};
+use ::axum::response::{IntoResponse, IntoResponseParts, Response, ResponseParts};
+
+use crate::headers;
+use crate::{Clock, Decision, KeyedLimiter, MonotonicClock, RateLimitError, RateLimitHeaders};
+
+// KeySource picks the key a request is rate limited by
+pub trait KeySource {
+    type Key: Hash + Eq + Clone + Send + Sync + 'static;
+
+    // Key returns the key of the request with parts, or None if it has none
+    fn key(parts: &Parts) -> Option<Self::Key>;
+
+    // Missing returns the rejection for requests without a key, by default
+    // MissingKey, which blames the client
+    fn missing() -> RateLimitRejection {
+        RateLimitRejection::MissingKey
+    }
+}
+
+// PeerIp keys requests by the IP address of the connected peer. The router
+// must be served with into_make_service_with_connect_info::<SocketAddr>.
+pub struct PeerIp;
+
+impl KeySource for PeerIp {
+    type Key = IpAddr;
+
+    fn key(parts: &Parts) -> Option<IpAddr> {
+        let ConnectInfo(addr) = parts.extensions.get::<ConnectInfo<SocketAddr>>()?;
+        Some(addr.ip())
+    }
+
+    // Every connection has a peer, so a missing one means the server was set
+    // up without connect info
+    fn missing() -> RateLimitRejection {
+        RateLimitRejection::MissingConnectInfo
+    }
+}
+
+// HeaderKey names the header a Header source reads keys from
+pub trait HeaderKey {
+    const NAME: &'static str;
+}
+
+// Header keys requests by the value of the header named by H, such as an
+// API key
+pub struct Header<H>(PhantomData<H>);
+
+impl<H: HeaderKey> KeySource for Header<H> {
+    type Key = HeaderValue;
+
+    fn key(parts: &Parts) -> Option<HeaderValue> {
+        parts.headers.get(H::NAME).cloned()
+    }
+}
+
+// Claims keys requests by the value of type T an authentication layer stored
+// in the request extensions, such as the subject of a verified token
+pub struct Claims<T>(PhantomData<T>);
+
+impl<T: Hash + Eq + Clone + Send + Sync + 'static> KeySource for Claims<T> {
+    type Key = T;
+
+    fn key(parts: &Parts) -> Option<T> {
+        parts.extensions.get::<T>().cloned()
+    }
+}
+
+// RateLimited admits a request if the KeyedLimiter in the request extensions
+// has a unit left for the key K picks for it, and rejects it with 429 Too
+// Many Requests otherwise. Limiters are added per route or router with
+// route_layer(Extension(Arc::new(limiter))), keyed by K's key type.
+pub struct RateLimited<K: KeySource, C = MonotonicClock> {
+    pub key: K::Key,
+    pub decision: Decision,
+    // Headers describing the remaining allowance, for handlers to return
+    pub headers: RateLimitHeaders,
+    clock: PhantomData<C>,
+}
+
+impl<K, C, S> FromRequestParts<S> for RateLimited<K, C>
+where
+    K: KeySource,
+    C: Clock + Clone + Send + Sync + 'static,
+    S: Send + Sync,
+{
+    type Rejection = RateLimitRejection;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let limiter = parts
+            .extensions
+            .get::<Arc<KeyedLimiter<K::Key, C>>>()
+            .ok_or(RateLimitRejection::MissingLimiter)?;
+        let key = K::key(parts).ok_or_else(K::missing)?;
+
+        let bucket = limiter.get(&key);
+        let decision = bucket.check();
+        let headers = RateLimitHeaders::new(&decision, &bucket.snapshot());
+        if !decision.allowed {
+            return Err(RateLimitRejection::TooManyRequests(headers));
+        }
+
+        Ok(RateLimited {
+            key,
+            decision,
+            headers,
+            clock: PhantomData,
+        })
+    }
+}
+
+// RateLimitRejection is the reason RateLimited rejected a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RateLimitRejection {
+    // The key's bucket is empty, answered with 429 and the rate limit headers
+    TooManyRequests(RateLimitHeaders),
+    // The request carries no key, answered with 400
+    MissingKey,
+    // No limiter for the key type was found in the request extensions,
+    // answered with 500
+    MissingLimiter,
+    // PeerIp found no peer address because the server was not set up with
+    // connect info, answered with 500
+    MissingConnectInfo,
+}
+
+impl IntoResponse for RateLimitRejection {
+    fn into_response(self) -> Response {
+        match self {
+            RateLimitRejection::TooManyRequests(headers) => {
+                (StatusCode::TOO_MANY_REQUESTS, headers, "too many requests").into_response()
+            }
+            RateLimitRejection::MissingKey => {
+                (StatusCode::BAD_REQUEST, "missing rate limit key").into_response()
+            }
+            RateLimitRejection::MissingLimiter => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "rate limiter not configured",
+            )
+                .into_response(),
+            RateLimitRejection::MissingConnectInfo => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "peer address not available",
+            )
+                .into_response(),
+        }
+    }
+}
+
+// RateLimitHeaders add the RateLimit-* and Retry-After headers to responses
+impl IntoResponseParts for RateLimitHeaders {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Infallible> {
+        for (name, value) in self.ietf() {
+            res.headers_mut().insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(&value).unwrap(),
+            );
+        }
+        Ok(res)
+    }
+}
+
+// RateLimitError answers requests rejected by a RateLimitLayer with 429 and
+// a Retry-After header, for use in a HandleErrorLayer
+impl IntoResponse for RateLimitError {
+    fn into_response(self) -> Response {
+        match self.retry_after() {
+            Duration::MAX => StatusCode::TOO_MANY_REQUESTS.into_response(),
+            d => {
+                let retry_after = [("retry-after", headers::seconds(d).to_string())];
+                (StatusCode::TOO_MANY_REQUESTS, retry_after).into_response()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::axum::http::Request;
+    use chrono::Duration as ChronoDuration;
+
+    use super::*;
+    use crate::timer::testing::block_on;
+    use crate::MockClock;
+
+    struct ApiKey;
+
+    impl HeaderKey for ApiKey {
+        const NAME: &'static str = "x-api-key";
+    }
+
+    fn extract<K: KeySource>(
+        request: Request<()>,
+    ) -> Result<RateLimited<K, MockClock>, RateLimitRejection> {
+        let (mut parts, _) = request.into_parts();
+        block_on(RateLimited::from_request_parts(&mut parts, &()))
+    }
+
+    #[test]
+    fn should_limit_by_header() {
+        let limiter = Arc::new(KeyedLimiter::<HeaderValue, _>::with_clock(
+            1,
+            ChronoDuration::seconds(10),
+            MockClock::new(),
+        ));
+        let request = |key: &str| {
+            Request::builder()
+                .header("x-api-key", key)
+                .extension(limiter.clone())
+                .body(())
+                .unwrap()
+        };
+
+        let admitted = extract::<Header<ApiKey>>(request("a")).unwrap();
+        assert_eq!(admitted.key, "a");
+        assert_eq!(admitted.headers.remaining, 0);
+        assert!(extract::<Header<ApiKey>>(request("b")).is_ok());
+
+        let rejection = extract::<Header<ApiKey>>(request("a")).err().unwrap();
+        let response = rejection.into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["ratelimit-limit"], "1");
+        assert_eq!(response.headers()["ratelimit-remaining"], "0");
+        assert_eq!(response.headers()["ratelimit-policy"], "1;w=10");
+        assert_eq!(response.headers()["retry-after"], "10");
+    }
+
+    #[test]
+    fn should_limit_by_peer_and_claims() {
+        let peers = Arc::new(KeyedLimiter::<IpAddr, _>::with_clock(
+            1,
+            ChronoDuration::seconds(1),
+            MockClock::new(),
+        ));
+        let addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let request = || {
+            Request::builder()
+                .extension(ConnectInfo(addr))
+                .extension(peers.clone())
+                .body(())
+                .unwrap()
+        };
+        assert_eq!(extract::<PeerIp>(request()).unwrap().key, addr.ip());
+        assert!(matches!(
+            extract::<PeerIp>(request()),
+            Err(RateLimitRejection::TooManyRequests(_))
+        ));
+
+        #[derive(Clone, Hash, PartialEq, Eq)]
+        struct Subject(u64);
+
+        let request = Request::builder().extension(Subject(7)).body(()).unwrap();
+        assert_eq!(
+            extract::<Claims<Subject>>(request).err(),
+            Some(RateLimitRejection::MissingLimiter)
+        );
+
+        // A missing peer address is the server's fault, a missing header the
+        // client's
+        let request = Request::builder()
+            .extension(peers.clone())
+            .body(())
+            .unwrap();
+        let rejection = extract::<PeerIp>(request).err().unwrap();
+        assert_eq!(rejection, RateLimitRejection::MissingConnectInfo);
+        assert_eq!(
+            rejection.into_response().status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        let keys = Arc::new(KeyedLimiter::<HeaderValue, _>::with_clock(
+            1,
+            ChronoDuration::seconds(1),
+            MockClock::new(),
+        ));
+        let request = Request::builder().extension(keys).body(()).unwrap();
+        let rejection = extract::<Header<ApiKey>>(request).err().unwrap();
+        assert_eq!(rejection, RateLimitRejection::MissingKey);
+        assert_eq!(rejection.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+}
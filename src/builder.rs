@@ -0,0 +1,253 @@
+use chrono::Duration;
+
+use crate::event::Events;
+use crate::{Clock, Level, Limiter, LimiterError, MonotonicClock};
+
+// LimiterBuilder configures a Limiter whose burst capacity and initial fill
+// differ from the defaults of Limiter::new
+pub struct LimiterBuilder<C = MonotonicClock> {
+    rate: i64,
+    per: Duration,
+    burst: Option<u64>,
+    empty: bool,
+    events: Events,
+    stats: bool,
+    clock: C,
+}
+
+impl Default for LimiterBuilder {
+    fn default() -> Self {
+        LimiterBuilder {
+            rate: 1,
+            per: Duration::seconds(1),
+            burst: None,
+            empty: false,
+            events: Events::default(),
+            stats: false,
+            clock: MonotonicClock::new(),
+        }
+    }
+}
+
+impl<C: Clock> LimiterBuilder<C> {
+    // Rate sets the number of units refilled per period, 1 by default
+    pub fn rate(mut self, rate: i64) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    // Per sets the period rate applies to, one second by default
+    pub fn per(mut self, per: Duration) -> Self {
+        self.per = per;
+        self
+    }
+
+    // Burst sets the number of units the bucket holds at most, which is the
+    // rate by default
+    pub fn burst(mut self, burst: u64) -> Self {
+        self.burst = Some(burst);
+        self
+    }
+
+    // StartEmpty makes the bucket start without any allowance instead of full
+    pub fn start_empty(mut self) -> Self {
+        self.empty = true;
+        self
+    }
+
+    // Name sets the name the limiter reports its decisions under
+    pub fn name(mut self, name: &str) -> Self {
+        self.events.name = Some(name.into());
+        self
+    }
+
+    // LogLevels sets the levels allowed and limited requests are reported at,
+    // with None turning reports off. Rejections are reported at debug level
+    // and admissions not at all by default.
+    pub fn log_levels(mut self, allowed: Option<Level>, limited: Option<Level>) -> Self {
+        self.events.allowed = allowed;
+        self.events.limited = limited;
+        self
+    }
+
+    // TrackStats makes the limiter count its decisions, see Limiter::stats
+    pub fn track_stats(mut self) -> Self {
+        self.stats = true;
+        self
+    }
+
+    // Clock sets the clock the limiter reads time from
+    pub fn clock<D: Clock>(self, clock: D) -> LimiterBuilder<D> {
+        LimiterBuilder {
+            rate: self.rate,
+            per: self.per,
+            burst: self.burst,
+            empty: self.empty,
+            events: self.events,
+            stats: self.stats,
+            clock,
+        }
+    }
+
+    // Build creates the limiter. Like Limiter::new, it falls back to a rate
+    // and burst of 1 and a period of one second for values below that.
+    //
+    // Panics if the maximum allowance cannot be represented, see try_build.
+    pub fn build(mut self) -> Limiter<C> {
+        self.per = or_default_period(self.per);
+        self.rate = self.rate.max(1);
+        self.burst = self.burst.map(|b| b.max(1));
+
+        self.try_build()
+            .expect("limiter configuration overflows 64 bits")
+    }
+
+    // TryBuild creates the limiter, reporting invalid configurations instead
+    // of adjusting them
+    pub fn try_build(self) -> Result<Limiter<C>, LimiterError> {
+        let (rate, nano, max) = config(self.rate, self.per, self.burst)?;
+        let allowance = if self.empty { 0 } else { max };
+
+        let mut limiter = Limiter::from_parts(
+            rate,
+            nano,
+            max,
+            allowance,
+            self.burst.is_some(),
+            self.events,
+            self.clock,
+        );
+        if self.stats {
+            limiter.stats = Some(Box::default());
+        }
+
+        Ok(limiter)
+    }
+}
+
+// config validates a limiter configuration, returning its rate, its period in
+// nanoseconds and its maximum allowance
+pub(crate) fn config(
+    rate: i64,
+    per: Duration,
+    burst: Option<u64>,
+) -> Result<(u64, u64, u64), LimiterError> {
+    if rate < 1 {
+        return Err(LimiterError::ZeroRate);
+    }
+    if burst == Some(0) {
+        return Err(LimiterError::ZeroBurst);
+    }
+
+    let nano = nanos(per)?;
+    let rate = rate as u64;
+    let max = burst
+        .unwrap_or(rate)
+        .checked_mul(nano)
+        .ok_or(LimiterError::Overflow)?;
+
+    Ok((rate, nano, max))
+}
+
+// nanos validates a period and converts it to nanoseconds
+pub(crate) fn nanos(per: Duration) -> Result<u64, LimiterError> {
+    if per <= Duration::zero() {
+        return Err(LimiterError::InvalidPeriod);
+    }
+
+    per.num_nanoseconds()
+        .map(|nano| nano as u64)
+        .ok_or(LimiterError::Overflow)
+}
+
+// or_default_period replaces empty or negative periods with one second, as
+// the infallible constructors do
+pub(crate) fn or_default_period(per: Duration) -> Duration {
+    if per <= Duration::zero() {
+        Duration::seconds(1)
+    } else {
+        per
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration as StdDuration;
+
+    use super::*;
+    use crate::MockClock;
+
+    #[test]
+    fn should_allow_smaller_bursts() {
+        let clock = MockClock::new();
+        let l = Limiter::builder()
+            .rate(100)
+            .per(Duration::seconds(1))
+            .burst(20)
+            .clock(clock.clone())
+            .build();
+
+        assert!(!l.limit_n(20));
+        assert!(l.limit());
+
+        // Refills at 100/s but never beyond 20
+        clock.advance(StdDuration::from_millis(100));
+        assert!(!l.limit_n(10));
+        clock.advance(StdDuration::from_secs(1));
+        assert!(l.limit_n(21));
+        assert!(!l.limit_n(20));
+    }
+
+    #[test]
+    fn should_allow_larger_bursts() {
+        let clock = MockClock::new();
+        let l = Limiter::builder()
+            .rate(10)
+            .burst(50)
+            .clock(clock.clone())
+            .build();
+
+        assert!(!l.limit_n(50));
+        clock.advance(StdDuration::from_secs(1));
+        assert!(!l.limit_n(10));
+        assert!(l.limit());
+
+        // Changing the rate keeps the configured burst
+        l.update_rate(20).unwrap();
+        assert_eq!(l.snapshot().capacity, 50);
+    }
+
+    #[test]
+    fn should_start_empty() {
+        let clock = MockClock::new();
+        let l = Limiter::builder()
+            .rate(10)
+            .start_empty()
+            .clock(clock.clone())
+            .build();
+
+        assert!(l.limit());
+        clock.advance(StdDuration::from_millis(300));
+        assert!(!l.limit_n(3));
+        assert!(l.limit());
+    }
+
+    #[test]
+    fn should_reject_overflowing_bursts() {
+        let built = Limiter::builder()
+            .rate(1)
+            .per(Duration::days(1))
+            .burst(1_000_000)
+            .try_build();
+        assert_eq!(built.err(), Some(LimiterError::Overflow));
+    }
+
+    #[test]
+    fn should_reject_instead_of_adjusting() {
+        let built = Limiter::builder().rate(10).burst(0).try_build();
+        assert_eq!(built.err(), Some(LimiterError::ZeroBurst));
+
+        let l = Limiter::builder().rate(10).burst(0).build();
+        assert_eq!(l.snapshot().capacity, 1);
+    }
+}
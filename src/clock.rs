@@ -0,0 +1,154 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Clock is a source of monotonic time for limiters, measured in nanoseconds
+// from an arbitrary origin.
+pub trait Clock {
+    // Now returns the current time in nanoseconds
+    fn now(&self) -> u64;
+
+    // Sleep parks the calling thread for d as measured by this clock
+    fn sleep(&self, d: Duration) {
+        thread::sleep(d)
+    }
+}
+
+// MonotonicClock reads std::time::Instant, so it never goes backwards when
+// the wall clock is stepped.
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicClock {
+    origin: Instant,
+}
+
+impl MonotonicClock {
+    // New creates a clock whose origin is the current instant
+    pub fn new() -> MonotonicClock {
+        MonotonicClock {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> u64 {
+        self.origin.elapsed().as_nanos() as u64
+    }
+}
+
+// SystemClock reads the wall clock as nanoseconds since the unix epoch. It
+// can step backwards, so prefer MonotonicClock unless calendar time matters.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64)
+    }
+}
+
+// CoarseClock caches a monotonic reading that a background thread refreshes
+// every resolution, trading precision for a single atomic load per call.
+#[derive(Debug, Clone)]
+pub struct CoarseClock {
+    now: Arc<AtomicU64>,
+}
+
+impl CoarseClock {
+    // New starts a coarse clock ticking at the given resolution. The ticker
+    // thread exits once every clone of the clock has been dropped.
+    pub fn new(resolution: Duration) -> CoarseClock {
+        let source = MonotonicClock::new();
+        let now = Arc::new(AtomicU64::new(source.now()));
+        let weak: Weak<AtomicU64> = Arc::downgrade(&now);
+
+        thread::Builder::new()
+            .name("limit-coarse-clock".into())
+            .spawn(move || loop {
+                thread::sleep(resolution);
+                match weak.upgrade() {
+                    Some(now) => now.store(source.now(), Ordering::Relaxed),
+                    None => return,
+                }
+            })
+            .expect("failed to spawn coarse clock thread");
+
+        CoarseClock { now }
+    }
+}
+
+impl Clock for CoarseClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::Relaxed)
+    }
+}
+
+// MockClock only moves when told to, for deterministic tests. Clones share
+// the same time.
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    now: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    // New creates a mock clock starting at zero
+    pub fn new() -> MockClock {
+        MockClock::default()
+    }
+
+    // Advance moves the clock forward by d
+    pub fn advance(&self, d: Duration) {
+        self.now.fetch_add(d.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    // Set moves the clock to d past its origin, possibly backwards
+    pub fn set(&self, d: Duration) {
+        self.now.store(d.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::Relaxed)
+    }
+
+    // Sleep returns immediately, advancing the mock time instead
+    fn sleep(&self, d: Duration) {
+        self.advance(d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_tick_coarse_clock() {
+        let c = CoarseClock::new(Duration::from_millis(1));
+        let start = c.now();
+
+        thread::sleep(Duration::from_millis(20));
+        assert!(c.now() > start);
+    }
+
+    #[test]
+    fn should_share_mock_time_between_clones() {
+        let c = MockClock::new();
+        let d = c.clone();
+
+        c.advance(Duration::from_secs(2));
+        assert_eq!(d.now(), 2_000_000_000);
+
+        d.set(Duration::from_secs(1));
+        assert_eq!(c.now(), 1_000_000_000);
+    }
+}
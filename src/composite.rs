@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+use crate::{Decision, LimiterError, RateLimiter, Snapshot};
+
+// check_all consumes n units from every limiter or from none of them. The
+// limiters are checked in order and those that already admitted the request
+// are rolled back with undo_n as soon as one rejects it, so a rejected
+// request may briefly hold units it never gets to use, but never keeps them.
+pub fn check_all(limiters: &[&dyn RateLimiter], n: u64) -> Decision {
+    let mut combined = Decision {
+        allowed: true,
+        remaining: u64::MAX,
+        retry_after: std::time::Duration::ZERO,
+        reset_after: std::time::Duration::ZERO,
+    };
+
+    for (i, l) in limiters.iter().enumerate() {
+        let d = l.check_n(n);
+        combined.remaining = combined.remaining.min(d.remaining);
+        combined.retry_after = combined.retry_after.max(d.retry_after);
+        combined.reset_after = combined.reset_after.max(d.reset_after);
+
+        if d.is_limited() {
+            for l in &limiters[..i] {
+                l.undo_n(n);
+            }
+
+            combined.allowed = false;
+            return combined;
+        }
+    }
+
+    combined
+}
+
+// Chain enforces several limits at once, such as a per-tenant limit nested
+// in a global one, admitting a request only if every limiter does. Chain
+// instances are thread-safe.
+pub struct Chain {
+    limiters: Vec<Arc<dyn RateLimiter>>,
+}
+
+impl Chain {
+    // New creates a chain checking limiters in the given order. Putting the
+    // most restrictive limiter first saves rollbacks.
+    pub fn new(limiters: Vec<Arc<dyn RateLimiter>>) -> Chain {
+        Chain { limiters }
+    }
+
+    // Limiters returns the limiters in the chain
+    pub fn limiters(&self) -> &[Arc<dyn RateLimiter>] {
+        &self.limiters
+    }
+}
+
+impl RateLimiter for Chain {
+    fn check_n(&self, n: u64) -> Decision {
+        let limiters: Vec<&dyn RateLimiter> = self.limiters.iter().map(|l| &**l).collect();
+        check_all(&limiters, n)
+    }
+
+    fn undo_n(&self, n: u64) {
+        for l in &self.limiters {
+            l.undo_n(n);
+        }
+    }
+
+    // Snapshot reports the tightest remaining allowance and the capacity,
+    // rate and period of the limiter it belongs to
+    fn snapshot(&self) -> Snapshot {
+        let snapshots: Vec<Snapshot> = self.limiters.iter().map(|l| l.snapshot()).collect();
+        let reset_after = snapshots.iter().map(|s| s.reset_after).max();
+
+        let mut tightest = snapshots
+            .into_iter()
+            .min_by_key(|s| s.remaining)
+            .unwrap_or(Snapshot {
+                capacity: u64::MAX,
+                remaining: u64::MAX,
+                rate: u64::MAX,
+                period: std::time::Duration::ZERO,
+                reset_after: std::time::Duration::ZERO,
+            });
+        tightest.reset_after = reset_after.unwrap_or_default();
+        tightest
+    }
+
+    // UpdateRate is unsupported because the levels of a chain have different
+    // rates. Update a single level through Limiters instead.
+    fn update_rate(&self, _rate: i64) -> Result<(), LimiterError> {
+        Err(LimiterError::Unsupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use std::time::Duration as StdDuration;
+
+    use super::*;
+    use crate::{KeyedLimiter, Limiter, MockClock};
+
+    #[test]
+    fn should_only_commit_when_all_allow() {
+        let clock = MockClock::new();
+        let tenant = Limiter::with_clock(5, Duration::seconds(1), clock.clone());
+        let global = Limiter::with_clock(3, Duration::seconds(1), clock.clone());
+
+        assert!(check_all(&[&tenant, &global], 2).allowed);
+
+        let d = check_all(&[&tenant, &global], 2);
+        assert!(d.is_limited());
+        assert_eq!(d.remaining, 1);
+        assert_eq!(d.retry_after, StdDuration::from_nanos(333_333_334));
+
+        // The tenant limiter got its two units back
+        assert!(!tenant.limit_n(3));
+        assert!(tenant.limit());
+    }
+
+    #[test]
+    fn should_chain_keyed_and_global_limits() {
+        let clock = MockClock::new();
+        let tenants = KeyedLimiter::with_clock(3, Duration::seconds(1), clock.clone());
+        let global = Limiter::with_clock(4, Duration::seconds(1), clock.clone());
+
+        assert!(tenants.check_all(&"a", 3, &[&global]).allowed);
+        assert!(tenants.check_all(&"a", 1, &[&global]).is_limited());
+        assert!(tenants.check_all(&"b", 1, &[&global]).allowed);
+        assert!(tenants.check_all(&"c", 1, &[&global]).is_limited());
+
+        // Tenant c was rolled back after the global limit rejected it
+        clock.advance(StdDuration::from_millis(250));
+        assert!(!tenants.limit_n(&"c", 3));
+    }
+
+    #[test]
+    fn should_act_as_a_single_limiter() {
+        let clock = MockClock::new();
+        let chain = Chain::new(vec![
+            Arc::new(Limiter::with_clock(10, Duration::seconds(1), clock.clone())),
+            Arc::new(Limiter::with_clock(4, Duration::seconds(1), clock.clone())),
+        ]);
+
+        assert!(!chain.limit_n(3));
+        assert_eq!(chain.snapshot().remaining, 1);
+        assert_eq!(chain.snapshot().capacity, 4);
+
+        chain.undo_n(3);
+        assert!(!chain.limit_n(4));
+        assert!(chain.limit());
+
+        assert_eq!(chain.update_rate(20), Err(LimiterError::Unsupported));
+        chain.limiters()[1].update_rate(8).unwrap();
+        assert_eq!(chain.limiters()[1].snapshot().rate, 8);
+        assert_eq!(chain.limiters()[0].snapshot().rate, 10);
+    }
+}
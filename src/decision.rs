@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+// Decision is the outcome of a rate limit check, carrying enough detail for
+// callers to tell clients when to come back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    // Whether the request was admitted
+    pub allowed: bool,
+    // Whole tokens left in the bucket after this decision
+    pub remaining: u64,
+    // Time until a request of the same cost would be admitted. Zero when the
+    // bucket already covers it, Duration::MAX when it never will.
+    pub retry_after: Duration,
+    // Time until the bucket is full again
+    pub reset_after: Duration,
+}
+
+impl Decision {
+    // IsAllowed returns true if the request was admitted
+    pub fn is_allowed(&self) -> bool {
+        self.allowed
+    }
+
+    // IsLimited returns true if the request was rejected
+    pub fn is_limited(&self) -> bool {
+        !self.allowed
+    }
+}
+
+// Snapshot describes a limiter's configuration and current state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    // Whole tokens the limiter can hold at most
+    pub capacity: u64,
+    // Whole tokens available right now
+    pub remaining: u64,
+    // Tokens admitted per period
+    pub rate: u64,
+    // Length of the period rate applies to
+    pub period: Duration,
+    // Time until the limiter is back at capacity
+    pub reset_after: Duration,
+}
+
+// nanos_until returns how long it takes to accumulate deficit units of
+// allowance at rate units per nanosecond, rounded up. Rates are at least 1.
+pub(crate) fn nanos_until(deficit: u64, rate: u64) -> Duration {
+    Duration::from_nanos(deficit.div_ceil(rate))
+}
@@ -0,0 +1,193 @@
+use std::sync::Arc;
+
+// Level is the severity rate limit decisions are reported at. Decisions are
+// emitted through the tracing or log crates when the corresponding cargo
+// feature is enabled, and discarded otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[cfg(feature = "tracing")]
+impl From<Level> for tracing::Level {
+    fn from(level: Level) -> tracing::Level {
+        match level {
+            Level::Error => tracing::Level::ERROR,
+            Level::Warn => tracing::Level::WARN,
+            Level::Info => tracing::Level::INFO,
+            Level::Debug => tracing::Level::DEBUG,
+            Level::Trace => tracing::Level::TRACE,
+        }
+    }
+}
+
+#[cfg(feature = "log")]
+impl From<Level> for log::Level {
+    fn from(level: Level) -> log::Level {
+        match level {
+            Level::Error => log::Level::Error,
+            Level::Warn => log::Level::Warn,
+            Level::Info => log::Level::Info,
+            Level::Debug => log::Level::Debug,
+            Level::Trace => log::Level::Trace,
+        }
+    }
+}
+
+// Events describes how a limiter reports its decisions: the name and key it
+// identifies itself by and the levels allowed and limited requests are
+// reported at, if at all. Rejections are reported at debug level and
+// admissions not at all by default.
+#[derive(Debug, Clone)]
+pub(crate) struct Events {
+    pub name: Option<Arc<str>>,
+    pub key: Option<Arc<str>>,
+    pub allowed: Option<Level>,
+    pub limited: Option<Level>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            name: None,
+            key: None,
+            allowed: None,
+            limited: Some(Level::Debug),
+        }
+    }
+}
+
+impl Events {
+    // emit reports a decision on cost units, leaving remaining units
+    #[cfg_attr(
+        not(any(feature = "tracing", feature = "log")),
+        allow(unused_variables)
+    )]
+    #[inline]
+    pub fn emit(&self, allowed: bool, cost: u64, remaining: u64) {
+        let level = match allowed {
+            true => self.allowed,
+            false => self.limited,
+        };
+        let Some(level) = level else {
+            return;
+        };
+
+        let name = self.name.as_deref().unwrap_or_default();
+        let key = self.key.as_deref().unwrap_or_default();
+
+        #[cfg(feature = "tracing")]
+        {
+            macro_rules! event {
+                ($level:expr) => {
+                    tracing::event!(
+                        target: "limit",
+                        $level,
+                        name,
+                        key,
+                        cost,
+                        remaining,
+                        allowed,
+                        "{}",
+                        if allowed { "allowed" } else { "rate limited" }
+                    )
+                };
+            }
+
+            match level {
+                Level::Error => event!(tracing::Level::ERROR),
+                Level::Warn => event!(tracing::Level::WARN),
+                Level::Info => event!(tracing::Level::INFO),
+                Level::Debug => event!(tracing::Level::DEBUG),
+                Level::Trace => event!(tracing::Level::TRACE),
+            }
+        }
+
+        #[cfg(feature = "log")]
+        log::log!(
+            target: "limit",
+            level.into(),
+            "{} name={name} key={key} cost={cost} remaining={remaining}",
+            if allowed { "allowed" } else { "rate limited" }
+        );
+    }
+}
+
+#[cfg(all(test, feature = "log"))]
+mod tests {
+    use std::sync::Mutex;
+
+    use chrono::Duration;
+
+    use crate::{KeyedLimiter, Level, Limiter, MockClock};
+
+    static RECORDS: Mutex<Vec<(log::Level, String)>> = Mutex::new(Vec::new());
+
+    struct Capture;
+
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            if record.target() == "limit" {
+                let line = record.args().to_string();
+                RECORDS.lock().unwrap().push((record.level(), line));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn should_log_decisions_at_configured_levels() {
+        log::set_logger(&Capture).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let l = Limiter::builder()
+            .rate(1)
+            .name("api")
+            .log_levels(Some(Level::Trace), Some(Level::Warn))
+            .clock(MockClock::new())
+            .build();
+        assert!(!l.limit());
+        assert!(l.limit_n(1));
+
+        let keyed = KeyedLimiter::with_clock(1, Duration::seconds(1), MockClock::new())
+            .with_name("login")
+            .with_key_label(|k: &u32| format!("user-{k}"));
+        assert!(!keyed.limit(&7));
+        assert!(keyed.limit(&7));
+
+        // Other tests may log concurrently through unnamed limiters
+        let records: Vec<_> = RECORDS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, line)| !line.contains("name= "))
+            .cloned()
+            .collect();
+        assert_eq!(
+            records,
+            [
+                (
+                    log::Level::Trace,
+                    "allowed name=api key= cost=1 remaining=0".to_string()
+                ),
+                (
+                    log::Level::Warn,
+                    "rate limited name=api key= cost=1 remaining=0".to_string()
+                ),
+                (
+                    log::Level::Debug,
+                    "rate limited name=login key=user-7 cost=1 remaining=0".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,386 @@
+use chrono::Duration;
+use portable_atomic::{AtomicU128, AtomicU64, Ordering};
+use std::time::Duration as StdDuration;
+
+use crate::builder;
+use crate::{AcquireError, Clock, Decision, LimiterError, MonotonicClock, RateLimiter, Snapshot};
+
+// GcraLimiter implements the generic cell rate algorithm. Its whole state is
+// a single theoretical arrival time (TAT), so every decision is one CAS.
+// Times are kept in nanoseconds with FRACTION fractional bits, so emission
+// intervals that are not whole nanoseconds don't change the rate.
+// GcraLimiter instances are thread-safe.
+pub struct GcraLimiter<C = MonotonicClock> {
+    tat: AtomicU128,
+    period: u64,
+    rate: AtomicU64,
+    clock: C,
+}
+
+// FRACTION is the number of fractional bits of GCRA times
+const FRACTION: u32 = 64;
+
+impl GcraLimiter {
+    // New creates a new GCRA limiter admitting rate units per period, with
+    // the same burst as a Limiter of the same rate
+    pub fn new(rate: i64, per: Duration) -> GcraLimiter {
+        GcraLimiter::with_clock(rate, per, MonotonicClock::new())
+    }
+
+    // TryNew is like New but reports rates below one, empty or negative
+    // periods and periods too long to represent in nanoseconds
+    pub fn try_new(rate: i64, per: Duration) -> Result<GcraLimiter, LimiterError> {
+        GcraLimiter::try_with_clock(rate, per, MonotonicClock::new())
+    }
+}
+
+impl<C: Clock> GcraLimiter<C> {
+    // WithClock creates a new GCRA limiter reading time from clock. Like
+    // Limiter::new it adjusts rates below one and empty or negative periods,
+    // and panics on periods too long to represent.
+    pub fn with_clock(rate: i64, per: Duration, clock: C) -> GcraLimiter<C> {
+        let per = builder::or_default_period(per);
+        GcraLimiter::try_with_clock(rate.max(1), per, clock)
+            .expect("limiter configuration overflows 64 bits")
+    }
+
+    // TryWithClock is like WithClock but reports invalid configurations
+    pub fn try_with_clock(
+        rate: i64,
+        per: Duration,
+        clock: C,
+    ) -> Result<GcraLimiter<C>, LimiterError> {
+        if rate < 1 {
+            return Err(LimiterError::ZeroRate);
+        }
+        let nano = builder::nanos(per)?;
+        let rate = rate as u64;
+
+        Ok(GcraLimiter {
+            tat: AtomicU128::new(fixed(clock.now())),
+            period: nano,
+            rate: AtomicU64::new(rate),
+            clock,
+        })
+    }
+
+    // Update rate updates the allowed rate, keeping the period. The backlog
+    // is measured in time against a tolerance of one period, so the used
+    // fraction of the burst carries over unchanged.
+    pub fn update_rate(&self, rate: i64) -> Result<(), LimiterError> {
+        if rate < 1 {
+            return Err(LimiterError::ZeroRate);
+        }
+        self.rate.store(rate as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Limit returns true if rate was exceeded
+    pub fn limit(&self) -> bool {
+        self.limit_n(1)
+    }
+
+    // LimitN returns true if consuming n units would exceed the rate. Costs
+    // larger than the burst are always limited.
+    pub fn limit_n(&self, n: u64) -> bool {
+        self.try_acquire(n).is_err()
+    }
+
+    // TryAcquire atomically consumes cost units, reporting why the request
+    // was rejected otherwise
+    pub fn try_acquire(&self, cost: u64) -> Result<(), AcquireError> {
+        let (interval, tolerance) = self.config();
+        let inc = interval.saturating_mul(cost as u128);
+        if inc > tolerance {
+            return Err(AcquireError::InsufficientCapacity {
+                cost,
+                capacity: units(tolerance / interval),
+            });
+        }
+
+        match self.take(inc, tolerance) {
+            (true, _) => Ok(()),
+            (false, _) => Err(AcquireError::RateLimited),
+        }
+    }
+
+    // CheckN consumes cost units if available and reports the full decision
+    pub fn check_n(&self, cost: u64) -> Decision {
+        let (interval, tolerance) = self.config();
+        let inc = interval.saturating_mul(cost as u128);
+        let now = fixed(self.clock.now());
+
+        let (allowed, tat) = if inc > tolerance {
+            (false, self.tat.load(Ordering::Relaxed).max(now))
+        } else {
+            self.take(inc, tolerance)
+        };
+
+        // Another request of the same cost fits once the TAT has moved within
+        // tolerance of the clock again
+        let retry_after = if inc > tolerance {
+            StdDuration::MAX
+        } else {
+            duration(
+                tat.saturating_add(inc)
+                    .saturating_sub(now.saturating_add(tolerance)),
+            )
+        };
+
+        Decision {
+            allowed,
+            remaining: units(now.saturating_add(tolerance).saturating_sub(tat) / interval),
+            retry_after,
+            reset_after: duration(tat.saturating_sub(now)),
+        }
+    }
+
+    // Snapshot reports the current state without consuming anything
+    pub fn snapshot(&self) -> Snapshot {
+        let rate = self.rate.load(Ordering::Relaxed);
+        let (interval, tolerance) = self.interval(rate);
+        let now = fixed(self.clock.now());
+        let tat = self.tat.load(Ordering::Relaxed).max(now);
+
+        Snapshot {
+            capacity: units(tolerance / interval),
+            remaining: units(now.saturating_add(tolerance).saturating_sub(tat) / interval),
+            rate,
+            period: StdDuration::from_nanos(self.period),
+            reset_after: duration(tat - now),
+        }
+    }
+
+    // Undo reverts the last Limit() call
+    pub fn undo(&self) {
+        self.undo_n(1)
+    }
+
+    // UndoN reverts a LimitN(n) call
+    pub fn undo_n(&self, n: u64) {
+        let (interval, _) = self.config();
+        let now = fixed(self.clock.now());
+        let mut tat = self.tat.load(Ordering::Relaxed);
+
+        // Moving the TAT before now would refund beyond a full bucket
+        loop {
+            let next = tat
+                .saturating_sub(interval.saturating_mul(n as u128))
+                .max(now);
+            match self
+                .tat
+                .compare_exchange_weak(tat, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(x) => tat = x,
+            }
+        }
+    }
+
+    // config returns the emission interval and the burst tolerance
+    fn config(&self) -> (u128, u128) {
+        self.interval(self.rate.load(Ordering::Relaxed))
+    }
+
+    // interval returns the emission interval at rate and the burst tolerance.
+    // The interval is rounded down, which admits at most one extra unit per
+    // 2^64 nanoseconds of emission intervals.
+    fn interval(&self, rate: u64) -> (u128, u128) {
+        let tolerance = fixed(self.period);
+        (tolerance / rate as u128, tolerance)
+    }
+
+    // take advances the TAT by inc if that stays within tolerance of now,
+    // returning whether it did and the resulting TAT
+    fn take(&self, inc: u128, tolerance: u128) -> (bool, u128) {
+        let now = fixed(self.clock.now());
+        let mut tat = self.tat.load(Ordering::Relaxed);
+
+        loop {
+            // A TAT in the past means the bucket is full
+            let next = tat.max(now).saturating_add(inc);
+            if next - now > tolerance {
+                return (false, tat.max(now));
+            }
+
+            match self
+                .tat
+                .compare_exchange_weak(tat, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return (true, next),
+                Err(x) => tat = x,
+            }
+        }
+    }
+}
+
+// fixed converts nanoseconds to a GCRA time
+fn fixed(nanos: u64) -> u128 {
+    (nanos as u128) << FRACTION
+}
+
+// duration converts a GCRA time span to a duration, rounded up
+fn duration(t: u128) -> StdDuration {
+    StdDuration::from_nanos(units(t.div_ceil(1 << FRACTION)))
+}
+
+// units narrows a unit count, saturating
+fn units(n: u128) -> u64 {
+    n.min(u64::MAX as u128) as u64
+}
+
+impl<C: Clock + Send + Sync> RateLimiter for GcraLimiter<C> {
+    fn check_n(&self, n: u64) -> Decision {
+        GcraLimiter::check_n(self, n)
+    }
+
+    fn undo_n(&self, n: u64) {
+        GcraLimiter::undo_n(self, n)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        GcraLimiter::snapshot(self)
+    }
+
+    fn update_rate(&self, rate: i64) -> Result<(), LimiterError> {
+        GcraLimiter::update_rate(self, rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration as StdDuration;
+
+    use super::*;
+    use crate::{Limiter, MockClock};
+
+    #[test]
+    fn should_limit_bursts() {
+        let l = GcraLimiter::with_clock(10, Duration::minutes(1), MockClock::new());
+
+        assert!(!l.limit_n(4));
+        assert!(!l.limit_n(6));
+        assert!(l.limit());
+
+        l.undo_n(2);
+        assert!(!l.limit_n(2));
+        assert!(l.limit());
+
+        assert_eq!(
+            l.try_acquire(11),
+            Err(AcquireError::InsufficientCapacity {
+                cost: 11,
+                capacity: 10
+            })
+        );
+    }
+
+    #[test]
+    fn should_match_token_bucket() {
+        let cases: [(i64, &[(u64, u64)]); 3] = [
+            (
+                10,
+                &[
+                    (0, 3),
+                    (0, 8),
+                    (150, 2),
+                    (50, 1),
+                    (0, 7),
+                    (2000, 10),
+                    (0, 1),
+                ],
+            ),
+            (
+                300_000_000,
+                &[
+                    (0, 300_000_000),
+                    (0, 1),
+                    (1, 300_000),
+                    (1, 300_001),
+                    (1000, 1),
+                ],
+            ),
+            (
+                10_000_000_000,
+                &[
+                    (0, 10_000_000_000),
+                    (0, 1),
+                    (100, 1_000_000_000),
+                    (0, 1),
+                    (900, 9_000_000_000),
+                ],
+            ),
+        ];
+
+        for (rate, steps) in cases {
+            let clock = MockClock::new();
+            let g = GcraLimiter::with_clock(rate, Duration::seconds(1), clock.clone());
+            let l = Limiter::with_clock(rate, Duration::seconds(1), clock.clone());
+
+            for &(step, cost) in steps {
+                clock.advance(StdDuration::from_millis(step));
+                assert_eq!(
+                    g.limit_n(cost),
+                    l.limit_n(cost),
+                    "{} at {:?}",
+                    rate,
+                    clock.now()
+                );
+                assert_eq!(g.snapshot(), l.snapshot(), "{} at {:?}", rate, clock.now());
+            }
+        }
+    }
+
+    #[test]
+    fn should_report_decisions_like_token_bucket() {
+        let clock = MockClock::new();
+        let g = GcraLimiter::with_clock(4, Duration::seconds(4), clock.clone());
+        let l = Limiter::with_clock(4, Duration::seconds(4), clock.clone());
+
+        for cost in [3, 2, 1, 5] {
+            assert_eq!(g.check_n(cost), l.check_n(cost));
+            clock.advance(StdDuration::from_millis(500));
+        }
+        assert_eq!(g.snapshot(), l.snapshot());
+
+        g.update_rate(8).unwrap();
+        l.update_rate(8).unwrap();
+        clock.advance(StdDuration::from_secs(4));
+        assert_eq!(g.snapshot().capacity, 8);
+        assert!(!g.limit_n(8));
+    }
+
+    #[test]
+    fn should_report_invalid_configurations() {
+        assert_eq!(
+            GcraLimiter::try_new(1, Duration::days(365 * 300)).err(),
+            Some(LimiterError::Overflow)
+        );
+        assert_eq!(
+            GcraLimiter::try_new(0, Duration::seconds(1)).err(),
+            Some(LimiterError::ZeroRate)
+        );
+        assert_eq!(
+            GcraLimiter::try_new(1, Duration::zero()).err(),
+            Some(LimiterError::InvalidPeriod)
+        );
+
+        let l = GcraLimiter::with_clock(0, Duration::zero(), MockClock::new());
+        assert_eq!(l.snapshot().capacity, 1);
+        assert_eq!(l.snapshot().period, StdDuration::from_secs(1));
+    }
+
+    #[test]
+    fn should_not_over_admit_concurrently() {
+        let l = GcraLimiter::with_clock(1000, Duration::hours(1), MockClock::new());
+
+        let admitted: usize = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| s.spawn(|| (0..500).filter(|_| !l.limit()).count()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+
+        assert_eq!(admitted, 1000);
+    }
+}
@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use crate::{Decision, Snapshot};
+
+// RateLimitHeaders holds the values of the RateLimit-* response headers
+// describing a decision, as drafted by the IETF httpapi working group, and
+// their legacy X-RateLimit-* equivalents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitHeaders {
+    // Units the limiter holds at most
+    pub limit: u64,
+    // Units left after the decision
+    pub remaining: u64,
+    // Seconds until the limiter has refilled completely
+    pub reset: u64,
+    // Quota and window the limiter enforces, e.g. "100;w=60"
+    pub policy: String,
+    // Seconds to wait before retrying a rejected request, unless it can never
+    // be admitted
+    pub retry_after: Option<u64>,
+}
+
+impl RateLimitHeaders {
+    // New describes decision, made by a limiter currently in state snapshot
+    pub fn new(decision: &Decision, snapshot: &Snapshot) -> RateLimitHeaders {
+        let retry_after = match decision.retry_after {
+            _ if decision.allowed => None,
+            Duration::MAX => None,
+            d => Some(seconds(d)),
+        };
+
+        RateLimitHeaders {
+            limit: snapshot.capacity,
+            remaining: decision.remaining,
+            reset: seconds(decision.reset_after),
+            policy: policy(snapshot),
+            retry_after,
+        }
+    }
+
+    // Ietf returns the RateLimit-* and Retry-After header names and values
+    pub fn ietf(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("RateLimit-Limit", self.limit.to_string()),
+            ("RateLimit-Remaining", self.remaining.to_string()),
+            ("RateLimit-Reset", self.reset.to_string()),
+            ("RateLimit-Policy", self.policy.clone()),
+        ];
+        if let Some(retry_after) = self.retry_after {
+            headers.push(("Retry-After", retry_after.to_string()));
+        }
+        headers
+    }
+
+    // Legacy returns the X-RateLimit-* and Retry-After header names and
+    // values. X-RateLimit-Reset holds seconds from now, like RateLimit-Reset,
+    // rather than a timestamp.
+    pub fn legacy(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("X-RateLimit-Limit", self.limit.to_string()),
+            ("X-RateLimit-Remaining", self.remaining.to_string()),
+            ("X-RateLimit-Reset", self.reset.to_string()),
+        ];
+        if let Some(retry_after) = self.retry_after {
+            headers.push(("Retry-After", retry_after.to_string()));
+        }
+        headers
+    }
+}
+
+// seconds rounds d up to whole seconds, so clients never retry too early
+pub(crate) fn seconds(d: Duration) -> u64 {
+    d.as_secs() + u64::from(d.subsec_nanos() > 0)
+}
+
+// policy formats the quota per window of whole seconds, scaling the rate to
+// the period rounded up to whole seconds. The window is never shorter than
+// the period, so the quota is never below the rate. Bursts differing from
+// the quota are reported as well.
+fn policy(snapshot: &Snapshot) -> String {
+    let period = snapshot.period.as_nanos().max(1);
+    let window = seconds(snapshot.period).max(1);
+    let quota = snapshot.rate as u128 * window as u128 * 1_000_000_000 / period;
+
+    match snapshot.capacity as u128 == quota {
+        true => format!("{quota};w={window}"),
+        false => format!("{quota};w={window};burst={}", snapshot.capacity),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration as ChronoDuration;
+
+    use super::*;
+    use crate::{Limiter, MockClock};
+
+    #[test]
+    fn should_describe_decisions() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(10, ChronoDuration::minutes(1), clock.clone());
+
+        let d = l.check_n(4);
+        let h = RateLimitHeaders::new(&d, &l.snapshot());
+        assert_eq!(
+            h.ietf(),
+            [
+                ("RateLimit-Limit", "10".to_string()),
+                ("RateLimit-Remaining", "6".to_string()),
+                ("RateLimit-Reset", "24".to_string()),
+                ("RateLimit-Policy", "10;w=60".to_string()),
+            ]
+        );
+
+        let d = l.check_n(8);
+        let h = RateLimitHeaders::new(&d, &l.snapshot());
+        assert_eq!(
+            h.legacy(),
+            [
+                ("X-RateLimit-Limit", "10".to_string()),
+                ("X-RateLimit-Remaining", "6".to_string()),
+                ("X-RateLimit-Reset", "24".to_string()),
+                ("Retry-After", "12".to_string()),
+            ]
+        );
+
+        // Costs beyond the capacity can never be retried
+        let d = l.check_n(11);
+        assert_eq!(RateLimitHeaders::new(&d, &l.snapshot()).retry_after, None);
+    }
+
+    #[test]
+    fn should_scale_policies_to_whole_seconds() {
+        let fast = Limiter::builder()
+            .rate(5)
+            .per(ChronoDuration::milliseconds(100))
+            .build();
+        let d = fast.check();
+        assert_eq!(
+            RateLimitHeaders::new(&d, &fast.snapshot()).policy,
+            "50;w=1;burst=5"
+        );
+
+        let bursty = Limiter::builder()
+            .rate(100)
+            .per(ChronoDuration::hours(1))
+            .burst(20)
+            .build();
+        let d = bursty.check();
+        assert_eq!(
+            RateLimitHeaders::new(&d, &bursty.snapshot()).policy,
+            "100;w=3600;burst=20"
+        );
+
+        for (rate, millis, expected) in [(1, 1500, "1;w=2"), (3, 2500, "3;w=3")] {
+            let l = Limiter::new(rate, ChronoDuration::milliseconds(millis));
+            let d = l.check();
+            assert_eq!(RateLimitHeaders::new(&d, &l.snapshot()).policy, expected);
+        }
+    }
+}
@@ -0,0 +1,649 @@
+use chrono::Duration;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration as StdDuration;
+
+use crate::builder;
+use crate::event::Events;
+use crate::{
+    check_all, AcquireError, Clock, Decision, Level, Limiter, LimiterError, MonotonicClock,
+    RateLimiter,
+};
+
+// Shards are swept for idle buckets once they have received as many new keys
+// since the last sweep as they hold, but never while smaller than this
+const MIN_SWEEP: usize = 64;
+
+struct Entry<C> {
+    limiter: Arc<Limiter<C>>,
+    last_seen: AtomicU64,
+    // Tells the entry apart from earlier ones for the same key
+    id: u64,
+}
+
+impl<C: Clock> Entry<C> {
+    // evictable returns true if nobody holds on to the bucket and it has
+    // refilled completely
+    fn evictable(&self) -> bool {
+        Arc::strong_count(&self.limiter) == 1 && self.limiter.is_full()
+    }
+}
+
+// Shard maps keys to buckets in a lock-free map. Creating and evicting
+// buckets is serialized by the queue lock, lookups never take it.
+struct Shard<K, C> {
+    map: papaya::HashMap<K, Entry<C>>,
+    queue: Mutex<Queue<K>>,
+}
+
+type Map<'a, K, C> = papaya::HashMapRef<'a, K, Entry<C>, RandomState, papaya::LocalGuard<'a>>;
+
+struct Queue<K> {
+    // Keys in the order they were created or last seen when queued, the least
+    // recently used first
+    order: VecDeque<Queued<K>>,
+    next_id: u64,
+    // New keys since the last sweep
+    inserted: usize,
+}
+
+// Queued is a shard's entry for key with id, as last seen at seen. Entries
+// seen since are queued again instead of evicted, and entries that are gone
+// are skipped.
+struct Queued<K> {
+    key: K,
+    id: u64,
+    seen: u64,
+}
+
+// Evictions counts the buckets a KeyedLimiter has dropped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Evictions {
+    // Buckets dropped because they had refilled to their maximum
+    pub idle: u64,
+    // Buckets dropped as least recently used to stay within max_keys
+    pub capacity: u64,
+}
+
+// KeyedLimiter keeps one Limiter per key, created on first use from a shared
+// rate configuration. Lookups of existing keys are lock-free; creating a
+// bucket locks a single shard.
+//
+// Buckets that have refilled to their maximum are indistinguishable from new
+// ones, so they are evicted as shards grow, by retain_recent() or by a
+// janitor thread. Lookups don't wait for evictions, so a request racing with
+// the eviction of its key's bucket may still be counted against the evicted
+// one. KeyedLimiter instances are thread-safe.
+pub struct KeyedLimiter<K, C = MonotonicClock> {
+    shards: Box<[Shard<K, C>]>,
+    hasher: RandomState,
+    rate: u64,
+    nano: u64,
+    max: u64,
+    max_per_shard: Option<usize>,
+    idle_evictions: AtomicU64,
+    capacity_evictions: AtomicU64,
+    events: Events,
+    label: Option<fn(&K) -> String>,
+    stats: bool,
+    clock: C,
+}
+
+impl<K: Hash + Eq + Clone> KeyedLimiter<K> {
+    // New creates a new keyed limiter giving every key its own bucket of rate
+    // units per period. Like Limiter::new it adjusts rates below one and
+    // empty or negative periods, and panics if the maximum allowance cannot
+    // be represented; use TryNew to have them reported instead.
+    pub fn new(rate: i64, per: Duration) -> KeyedLimiter<K> {
+        KeyedLimiter::with_clock(rate, per, MonotonicClock::new())
+    }
+
+    // TryNew is like New but reports invalid configurations
+    pub fn try_new(rate: i64, per: Duration) -> Result<KeyedLimiter<K>, LimiterError> {
+        KeyedLimiter::try_with_clock(rate, per, MonotonicClock::new())
+    }
+}
+
+impl<K: Hash + Eq + Clone, C: Clock + Clone> KeyedLimiter<K, C> {
+    // WithClock creates a new keyed limiter whose buckets read time from clock
+    pub fn with_clock(rate: i64, per: Duration, clock: C) -> KeyedLimiter<K, C> {
+        KeyedLimiter::with_shards(rate, per, clock, default_shards())
+    }
+
+    // TryWithClock is like WithClock but reports invalid configurations
+    pub fn try_with_clock(
+        rate: i64,
+        per: Duration,
+        clock: C,
+    ) -> Result<KeyedLimiter<K, C>, LimiterError> {
+        KeyedLimiter::try_with_shards(rate, per, clock, default_shards())
+    }
+
+    // WithShards is like WithClock but with an explicit number of shards
+    pub fn with_shards(rate: i64, per: Duration, clock: C, shards: usize) -> KeyedLimiter<K, C> {
+        let per = builder::or_default_period(per);
+        KeyedLimiter::try_with_shards(rate.max(1), per, clock, shards)
+            .expect("limiter configuration overflows 64 bits")
+    }
+
+    // TryWithShards is like WithShards but reports invalid configurations.
+    // Every bucket is created from the configuration validated here.
+    pub fn try_with_shards(
+        rate: i64,
+        per: Duration,
+        clock: C,
+        shards: usize,
+    ) -> Result<KeyedLimiter<K, C>, LimiterError> {
+        let (rate, nano, max) = builder::config(rate, per, None)?;
+
+        Ok(KeyedLimiter {
+            shards: (0..shards.max(1))
+                .map(|_| Shard {
+                    map: papaya::HashMap::new(),
+                    queue: Mutex::new(Queue {
+                        order: VecDeque::new(),
+                        next_id: 0,
+                        inserted: 0,
+                    }),
+                })
+                .collect(),
+            hasher: RandomState::new(),
+            rate,
+            nano,
+            max,
+            max_per_shard: None,
+            idle_evictions: AtomicU64::new(0),
+            capacity_evictions: AtomicU64::new(0),
+            events: Events::default(),
+            label: None,
+            stats: false,
+            clock,
+        })
+    }
+
+    // WithMaxKeys caps the number of buckets kept. Once a shard holds its
+    // share of max, each new key evicts the least recently used bucket, so
+    // the cap is enforced per shard and approximate.
+    pub fn with_max_keys(mut self, max: usize) -> KeyedLimiter<K, C> {
+        self.max_per_shard = Some(max.div_ceil(self.shards.len()).max(1));
+        self
+    }
+
+    // WithName sets the name the buckets report their decisions under
+    pub fn with_name(mut self, name: &str) -> KeyedLimiter<K, C> {
+        self.events.name = Some(name.into());
+        self
+    }
+
+    // WithKeyLabel makes buckets report their key as formatted by label. Keys
+    // are left out of reports otherwise, as they need not be printable.
+    pub fn with_key_label(mut self, label: fn(&K) -> String) -> KeyedLimiter<K, C> {
+        self.label = Some(label);
+        self
+    }
+
+    // WithLogLevels sets the levels allowed and limited requests are reported
+    // at, see LimiterBuilder::log_levels
+    pub fn with_log_levels(
+        mut self,
+        allowed: Option<Level>,
+        limited: Option<Level>,
+    ) -> KeyedLimiter<K, C> {
+        self.events.allowed = allowed;
+        self.events.limited = limited;
+        self
+    }
+
+    // WithStats makes every bucket count its decisions, see Limiter::stats.
+    // Counts are lost when a bucket is evicted.
+    pub fn with_stats(mut self) -> KeyedLimiter<K, C> {
+        self.stats = true;
+        self
+    }
+
+    // Get returns the bucket for key, creating it if needed. Buckets are not
+    // evicted while the returned Arc is alive, unless an eviction racing with
+    // Get had already picked them.
+    pub fn get(&self, key: &K) -> Arc<Limiter<C>> {
+        self.with(key, Arc::clone)
+    }
+
+    // Limit returns true if key exceeded its rate
+    pub fn limit(&self, key: &K) -> bool {
+        self.limit_n(key, 1)
+    }
+
+    // LimitN returns true if consuming n units would exceed key's rate
+    pub fn limit_n(&self, key: &K, n: u64) -> bool {
+        self.with(key, |l| l.limit_n(n))
+    }
+
+    // TryAcquire atomically deducts cost units from key's allowance
+    pub fn try_acquire(&self, key: &K, cost: u64) -> Result<(), AcquireError> {
+        self.with(key, |l| l.try_acquire(cost))
+    }
+
+    // CheckN consumes cost units of key's allowance if available and reports
+    // the full decision
+    pub fn check_n(&self, key: &K, cost: u64) -> Decision {
+        self.with(key, |l| l.check_n(cost))
+    }
+
+    // UndoN reverts a LimitN(key, n) call
+    pub fn undo_n(&self, key: &K, n: u64) {
+        self.with(key, |l| l.undo_n(n))
+    }
+
+    // Remove forgets key's bucket, returning it if there was one
+    pub fn remove(&self, key: &K) -> Option<Arc<Limiter<C>>> {
+        let map = self.shard(key).map.pin();
+        map.remove(key).map(|e| e.limiter.clone())
+    }
+
+    // Len returns the number of keys with a bucket
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.map.len()).sum()
+    }
+
+    // IsEmpty returns true if no key has a bucket
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.map.is_empty())
+    }
+
+    // ForEach calls f with every key and its bucket. Buckets created or
+    // removed meanwhile may or may not be visited.
+    pub fn for_each(&self, mut f: impl FnMut(&K, &Limiter<C>)) {
+        for shard in self.shards.iter() {
+            for (key, e) in &shard.map.pin() {
+                f(key, &e.limiter);
+            }
+        }
+    }
+
+    // RetainRecent drops every bucket that has refilled to its maximum,
+    // returning how many were dropped
+    pub fn retain_recent(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| self.evict_idle(&s.map.pin(), &mut s.queue.lock().unwrap()))
+            .sum()
+    }
+
+    // Evictions returns how many buckets have been evicted so far
+    pub fn evictions(&self) -> Evictions {
+        Evictions {
+            idle: self.idle_evictions.load(Ordering::Relaxed),
+            capacity: self.capacity_evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    // with runs f on key's bucket, only taking the shard's queue lock if the
+    // bucket has to be created
+    fn with<T>(&self, key: &K, f: impl FnOnce(&Arc<Limiter<C>>) -> T) -> T {
+        let shard = self.shard(key);
+        let map = shard.map.pin();
+        let now = self.clock.now();
+
+        let e = match map.get(key) {
+            Some(e) => e,
+            None => self.insert(&map, &mut shard.queue.lock().unwrap(), key, now),
+        };
+        e.last_seen.store(now, Ordering::Relaxed);
+        f(&e.limiter)
+    }
+
+    // insert returns key's bucket, creating it unless another caller did
+    // while we waited for the queue lock
+    fn insert<'a>(
+        &self,
+        map: &'a Map<'a, K, C>,
+        queue: &mut Queue<K>,
+        key: &K,
+        now: u64,
+    ) -> &'a Entry<C> {
+        if let Some(e) = map.get(key) {
+            return e;
+        }
+        self.make_room(map, queue);
+
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.order.push_back(Queued {
+            key: key.clone(),
+            id,
+            seen: now,
+        });
+        map.get_or_insert(key.clone(), self.entry(key, id, now))
+    }
+
+    // entry creates the bucket for key
+    fn entry(&self, key: &K, id: u64, now: u64) -> Entry<C> {
+        let mut events = self.events.clone();
+        events.key = self.label.map(|label| label(key).into());
+
+        let mut limiter = Limiter::from_parts(
+            self.rate,
+            self.nano,
+            self.max,
+            self.max,
+            false,
+            events,
+            self.clock.clone(),
+        );
+        if self.stats {
+            limiter.stats = Some(Box::default());
+        }
+
+        Entry {
+            limiter: Arc::new(limiter),
+            last_seen: AtomicU64::new(now),
+            id,
+        }
+    }
+
+    // make_room evicts buckets from a shard about to receive a new key. Idle
+    // buckets are swept only every so often, and capped shards drop the front
+    // of their recency queue, so each new key costs amortized constant time.
+    fn make_room(&self, map: &Map<'_, K, C>, queue: &mut Queue<K>) {
+        if queue.inserted >= map.len().max(MIN_SWEEP) {
+            self.evict_idle(map, queue);
+        }
+        queue.inserted += 1;
+
+        let Some(max) = self.max_per_shard else {
+            return;
+        };
+
+        // Buckets seen since they were queued go to the back, paid for by
+        // the lookups that saw them. Buckets in use go to the back too, and
+        // if every bucket is in use we go over the cap rather than lose one.
+        let mut budget = queue.order.len();
+        while map.len() >= max && budget > 0 {
+            budget -= 1;
+            let Some(q) = queue.order.pop_front() else {
+                return;
+            };
+            let Some(e) = map.get(&q.key).filter(|e| e.id == q.id) else {
+                continue;
+            };
+
+            let seen = e.last_seen.load(Ordering::Relaxed);
+            if seen != q.seen || Arc::strong_count(&e.limiter) > 1 {
+                queue.order.push_back(Queued { seen, ..q });
+                continue;
+            }
+
+            map.remove(&q.key);
+            self.capacity_evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // evict_idle drops a shard's idle buckets, returning how many
+    fn evict_idle(&self, map: &Map<'_, K, C>, queue: &mut Queue<K>) -> usize {
+        let mut evicted = 0;
+        map.retain(|_, e| {
+            let evict = e.evictable();
+            evicted += usize::from(evict);
+            !evict
+        });
+
+        // Forget queued keys that are gone, including removed ones
+        queue
+            .order
+            .retain(|q| map.get(&q.key).is_some_and(|e| e.id == q.id));
+        queue.inserted = 0;
+
+        self.idle_evictions
+            .fetch_add(evicted as u64, Ordering::Relaxed);
+        evicted
+    }
+
+    fn shard(&self, key: &K) -> &Shard<K, C> {
+        let i = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[i]
+    }
+}
+
+impl<K: Hash + Eq + Clone, C: Clock + Clone + Send + Sync> KeyedLimiter<K, C> {
+    // CheckAll consumes cost units from key's bucket and every one of parents,
+    // or from none of them, see check_all
+    pub fn check_all(&self, key: &K, cost: u64, parents: &[&dyn RateLimiter]) -> Decision {
+        self.with(key, |l| {
+            let mut limiters: Vec<&dyn RateLimiter> = Vec::with_capacity(parents.len() + 1);
+            limiters.push(&**l);
+            limiters.extend_from_slice(parents);
+            check_all(&limiters, cost)
+        })
+    }
+}
+
+impl<K, C> KeyedLimiter<K, C>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    C: Clock + Clone + Send + Sync + 'static,
+{
+    // SpawnJanitor starts a thread calling retain_recent() every interval.
+    // It stops when the returned handle or the limiter is dropped.
+    pub fn spawn_janitor(self: &Arc<Self>, interval: StdDuration) -> Janitor {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let limiter = Arc::downgrade(self);
+
+        thread::Builder::new()
+            .name("limit-keyed-janitor".into())
+            .spawn(move || loop {
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => match limiter.upgrade() {
+                        Some(l) => {
+                            l.retain_recent();
+                        }
+                        None => return,
+                    },
+                    _ => return,
+                }
+            })
+            .expect("failed to spawn janitor thread");
+
+        Janitor { _stop: stop }
+    }
+}
+
+// default_shards returns four shards per available CPU
+fn default_shards() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get()) * 4
+}
+
+// Janitor keeps a KeyedLimiter's janitor thread running until dropped
+pub struct Janitor {
+    _stop: mpsc::Sender<()>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+
+    #[test]
+    fn should_limit_keys_independently() {
+        let clock = MockClock::new();
+        let l = KeyedLimiter::with_clock(3, Duration::seconds(1), clock.clone());
+
+        assert!(!l.limit_n(&"alice", 3));
+        assert!(l.limit(&"alice"));
+        assert!(!l.limit_n(&"bob", 3));
+        assert_eq!(l.len(), 2);
+
+        l.undo_n(&"alice", 1);
+        assert!(!l.limit(&"alice"));
+
+        clock.advance(StdDuration::from_secs(1));
+        assert!(l.check_n(&"bob", 3).allowed);
+    }
+
+    #[test]
+    fn should_validate_configuration_up_front() {
+        assert_eq!(
+            KeyedLimiter::<u32>::try_new(1, Duration::days(365 * 300)).err(),
+            Some(LimiterError::Overflow)
+        );
+        assert_eq!(
+            KeyedLimiter::<u32>::try_new(0, Duration::seconds(1)).err(),
+            Some(LimiterError::ZeroRate)
+        );
+        assert_eq!(
+            KeyedLimiter::<u32, _>::try_with_clock(1, Duration::zero(), MockClock::new()).err(),
+            Some(LimiterError::InvalidPeriod)
+        );
+
+        // New adjusts what it can, like Limiter::new
+        let l = KeyedLimiter::with_clock(0, Duration::zero(), MockClock::new());
+        assert_eq!(l.get(&"a").snapshot().capacity, 1);
+        assert_eq!(l.get(&"a").snapshot().period, StdDuration::from_secs(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "overflows")]
+    fn should_panic_on_construction_not_first_use() {
+        KeyedLimiter::<u32>::new(1, Duration::days(365 * 300));
+    }
+
+    #[test]
+    fn should_share_buckets_between_callers() {
+        let l = KeyedLimiter::with_shards(100, Duration::hours(1), MockClock::new(), 2);
+
+        let admitted: usize = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| s.spawn(|| (0..50).filter(|i| !l.limit(&(i % 2))).count()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+
+        assert_eq!(admitted, 200);
+        assert!(l.get(&0).limit());
+        assert!(l.remove(&0).is_some());
+        assert!(!l.limit(&0));
+    }
+
+    #[test]
+    fn should_look_up_existing_keys_without_locking() {
+        let l = KeyedLimiter::with_shards(3, Duration::seconds(1), MockClock::new(), 1);
+        assert!(!l.limit(&"a"));
+
+        // Existing keys are served while the shard is locked for creation,
+        // and buckets can be used while they are being visited
+        let queue = l.shards[0].queue.lock().unwrap();
+        assert!(!l.limit(&"a"));
+        l.for_each(|k, _| assert!(!l.limit(k)));
+        drop(queue);
+
+        assert!(l.limit(&"a"));
+        assert!(!l.limit(&"b"));
+    }
+
+    #[test]
+    fn should_evict_idle_buckets() {
+        let clock = MockClock::new();
+        let l = KeyedLimiter::with_clock(10, Duration::seconds(1), clock.clone());
+
+        for i in 0..100 {
+            assert!(!l.limit(&i));
+        }
+        let held = l.get(&0);
+
+        clock.advance(StdDuration::from_millis(50));
+        assert!(!l.limit_n(&1, 5));
+        assert_eq!(l.retain_recent(), 0);
+
+        clock.advance(StdDuration::from_millis(50));
+        assert_eq!(l.retain_recent(), 98);
+        assert_eq!(l.len(), 2);
+        assert_eq!(l.evictions().idle, 98);
+
+        drop(held);
+        clock.advance(StdDuration::from_secs(1));
+        assert_eq!(l.retain_recent(), 2);
+        assert!(l.is_empty());
+    }
+
+    #[test]
+    fn should_sweep_growing_shards() {
+        let clock = MockClock::new();
+        let l = KeyedLimiter::with_shards(10, Duration::seconds(1), clock.clone(), 1);
+
+        for i in 0..MIN_SWEEP {
+            assert!(!l.limit(&i));
+        }
+
+        clock.advance(StdDuration::from_secs(1));
+        assert!(!l.limit(&MIN_SWEEP));
+        assert_eq!(l.len(), 1);
+    }
+
+    #[test]
+    fn should_cap_keys_by_recency() {
+        let clock = MockClock::new();
+        let l =
+            KeyedLimiter::with_shards(10, Duration::seconds(1), clock.clone(), 1).with_max_keys(2);
+
+        assert!(!l.limit(&"a"));
+        clock.advance(StdDuration::from_millis(1));
+        assert!(!l.limit(&"b"));
+        clock.advance(StdDuration::from_millis(1));
+        assert!(!l.limit(&"a"));
+        clock.advance(StdDuration::from_millis(1));
+        assert!(!l.limit(&"c"));
+
+        assert_eq!(l.len(), 2);
+        assert!(l.remove(&"b").is_none());
+        assert_eq!(
+            l.evictions(),
+            Evictions {
+                idle: 0,
+                capacity: 1
+            }
+        );
+    }
+
+    #[test]
+    fn should_stay_bounded_under_scanning() {
+        let clock = MockClock::new();
+        let l = KeyedLimiter::with_shards(10, Duration::seconds(1), clock.clone(), 1)
+            .with_max_keys(100);
+        let held = l.get(&u32::MAX);
+
+        // Every key is new and drained, so none is idle, while one hot key
+        // keeps being used
+        for i in 0..10_000 {
+            clock.advance(StdDuration::from_nanos(1));
+            assert!(!l.limit_n(&i, 10));
+            assert!(!l.limit_n(&0, 0));
+        }
+
+        assert_eq!(l.len(), 100);
+        assert!(l.remove(&0).is_some());
+        assert!(l.remove(&u32::MAX).is_some());
+        assert!(l.remove(&9_999).is_some());
+        assert_eq!(l.evictions().capacity, 10_000 - 99);
+        drop(held);
+    }
+
+    #[test]
+    fn should_sweep_from_janitor() {
+        let l = Arc::new(KeyedLimiter::new(1000, Duration::milliseconds(1)));
+        let janitor = l.spawn_janitor(StdDuration::from_millis(5));
+
+        assert!(!l.limit(&"a"));
+        for _ in 0..200 {
+            if l.is_empty() {
+                break;
+            }
+            thread::sleep(StdDuration::from_millis(5));
+        }
+        assert!(l.is_empty());
+        drop(janitor);
+    }
+}
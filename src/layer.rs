@@ -0,0 +1,469 @@
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{Clock, Decision, DefaultTimer, KeyedLimiter, RateLimiter, Timer};
+
+// BoxError is the error type of rate limited services, holding either the
+// inner service's error or a RateLimitError
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Bucket picks the limiter a request draws a unit from. It is implemented
+// for a single shared limiter and for Keyed.
+pub trait Bucket<Req> {
+    // Check consumes a unit for req if available
+    fn check(&self, req: &Req) -> Decision;
+
+    // CheckAny consumes a unit on behalf of whichever request comes next, or
+    // returns None if the bucket depends on the request
+    fn check_any(&self) -> Option<Decision> {
+        None
+    }
+
+    // UndoAny returns a unit taken by CheckAny that no request used
+    fn undo_any(&self) {}
+}
+
+impl<Req, L: RateLimiter + ?Sized> Bucket<Req> for Arc<L> {
+    fn check(&self, _: &Req) -> Decision {
+        RateLimiter::check(&**self)
+    }
+
+    fn check_any(&self) -> Option<Decision> {
+        Some(RateLimiter::check(&**self))
+    }
+
+    fn undo_any(&self) {
+        RateLimiter::undo(&**self)
+    }
+}
+
+// Keyed draws each request from the bucket of a KeyedLimiter picked by a
+// key extractor
+pub struct Keyed<K, C, F> {
+    limiter: Arc<KeyedLimiter<K, C>>,
+    key: F,
+}
+
+impl<K, C, F: Clone> Clone for Keyed<K, C, F> {
+    fn clone(&self) -> Self {
+        Keyed {
+            limiter: self.limiter.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+impl<Req, K, C, F> Bucket<Req> for Keyed<K, C, F>
+where
+    K: Hash + Eq + Clone,
+    C: Clock + Clone,
+    F: Fn(&Req) -> K,
+{
+    fn check(&self, req: &Req) -> Decision {
+        self.limiter.check_n(&(self.key)(req), 1)
+    }
+}
+
+// RateLimitError is returned by a rate limited service in place of calling
+// the inner service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitError {
+    pub decision: Decision,
+}
+
+impl RateLimitError {
+    // RetryAfter returns how long to wait before the request may be admitted
+    pub fn retry_after(&self) -> Duration {
+        self.decision.retry_after
+    }
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rate limited, retry after {:?}",
+            self.decision.retry_after
+        )
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+// RateLimitLayer wraps services in RateLimit. By default a service waits in
+// poll_ready until its limiter admits the next request, pushing back on the
+// caller; reject() makes it fail rate limited requests with RateLimitError
+// instead. Keyed limiters always reject, as the key is not known before the
+// request is.
+pub struct RateLimitLayer<B, T = DefaultTimer> {
+    bucket: B,
+    reject: bool,
+    timer: T,
+}
+
+impl<L: RateLimiter + ?Sized> RateLimitLayer<Arc<L>> {
+    // New creates a layer drawing every request from limiter
+    pub fn new(limiter: Arc<L>) -> Self {
+        RateLimitLayer {
+            bucket: limiter,
+            reject: false,
+            timer: DefaultTimer::default(),
+        }
+    }
+}
+
+impl<K, C, F> RateLimitLayer<Keyed<K, C, F>> {
+    // Keyed creates a layer drawing each request from the bucket of limiter
+    // that key picks for it, rejecting requests over the limit
+    pub fn keyed(limiter: Arc<KeyedLimiter<K, C>>, key: F) -> Self {
+        RateLimitLayer {
+            bucket: Keyed { limiter, key },
+            reject: true,
+            timer: DefaultTimer::default(),
+        }
+    }
+}
+
+impl<B, T> RateLimitLayer<B, T> {
+    // Reject makes rate limited requests fail immediately with
+    // RateLimitError instead of waiting in poll_ready
+    pub fn reject(mut self) -> Self {
+        self.reject = true;
+        self
+    }
+
+    // WithTimer sets the timer services wait on in poll_ready
+    pub fn with_timer<U: Timer>(self, timer: U) -> RateLimitLayer<B, U> {
+        RateLimitLayer {
+            bucket: self.bucket,
+            reject: self.reject,
+            timer,
+        }
+    }
+}
+
+impl<B: Clone, T: Clone> Clone for RateLimitLayer<B, T> {
+    fn clone(&self) -> Self {
+        RateLimitLayer {
+            bucket: self.bucket.clone(),
+            reject: self.reject,
+            timer: self.timer.clone(),
+        }
+    }
+}
+
+impl<S, B: Clone, T: Timer + Clone> Layer<S> for RateLimitLayer<B, T> {
+    type Service = RateLimit<S, B, T>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            bucket: self.bucket.clone(),
+            reject: self.reject,
+            timer: self.timer.clone(),
+            delay: None,
+            permit: None,
+        }
+    }
+}
+
+// RateLimit is a service admitting requests to inner at the rate of its
+// limiter, see RateLimitLayer. A unit taken by poll_ready is returned if the
+// service is dropped before calling with it.
+pub struct RateLimit<S, B, T: Timer = DefaultTimer> {
+    inner: S,
+    bucket: B,
+    reject: bool,
+    timer: T,
+    delay: Option<Pin<Box<T::Delay>>>,
+    // Set once poll_ready has taken a unit for the next call, holding the
+    // function that returns it to the bucket unused
+    permit: Option<fn(&B)>,
+}
+
+impl<S, B, T: Timer> RateLimit<S, B, T> {
+    // refund returns the unit taken by poll_ready, if any
+    fn refund(&mut self) {
+        if let Some(undo) = self.permit.take() {
+            undo(&self.bucket);
+        }
+    }
+}
+
+impl<S: Clone, B: Clone, T: Timer + Clone> Clone for RateLimit<S, B, T> {
+    // Clones start without the permit, which belongs to the original
+    fn clone(&self) -> Self {
+        RateLimit {
+            inner: self.inner.clone(),
+            bucket: self.bucket.clone(),
+            reject: self.reject,
+            timer: self.timer.clone(),
+            delay: None,
+            permit: None,
+        }
+    }
+}
+
+impl<S, B, T: Timer> Drop for RateLimit<S, B, T> {
+    fn drop(&mut self) {
+        self.refund();
+    }
+}
+
+impl<S, B, T, Req> Service<Req> for RateLimit<S, B, T>
+where
+    S: Service<Req>,
+    S::Error: Into<BoxError>,
+    B: Bucket<Req>,
+    T: Timer,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Wait for the inner service first, so no unit is taken for a call
+        // that cannot happen
+        if let Err(err) = ready!(self.inner.poll_ready(cx)) {
+            self.refund();
+            return Poll::Ready(Err(err.into()));
+        }
+
+        while !self.reject && self.permit.is_none() {
+            if let Some(delay) = &mut self.delay {
+                if delay.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.delay = None;
+            }
+
+            match self.bucket.check_any() {
+                // The bucket depends on the request, so call checks it
+                None => break,
+                Some(d) if d.allowed => self.permit = Some(B::undo_any),
+                Some(d) if d.retry_after == Duration::MAX => {
+                    return Poll::Ready(Err(RateLimitError { decision: d }.into()))
+                }
+                Some(d) => self.delay = Some(Box::pin(self.timer.delay(d.retry_after))),
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        if self.permit.take().is_none() {
+            let decision = self.bucket.check(&req);
+            if !decision.allowed {
+                return ResponseFuture::limited(RateLimitError { decision });
+            }
+        }
+
+        ResponseFuture::inner(self.inner.call(req))
+    }
+}
+
+pin_project! {
+    // ResponseFuture resolves to the inner service's response, or to a
+    // RateLimitError if the request was rejected
+    pub struct ResponseFuture<F> {
+        #[pin]
+        kind: Kind<F>,
+    }
+}
+
+pin_project! {
+    #[project = KindProj]
+    enum Kind<F> {
+        Inner { #[pin] future: F },
+        Limited { error: Option<RateLimitError> },
+    }
+}
+
+impl<F> ResponseFuture<F> {
+    fn inner(future: F) -> Self {
+        ResponseFuture {
+            kind: Kind::Inner { future },
+        }
+    }
+
+    fn limited(error: RateLimitError) -> Self {
+        ResponseFuture {
+            kind: Kind::Limited { error: Some(error) },
+        }
+    }
+}
+
+impl<F, R, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<R, E>>,
+    E: Into<BoxError>,
+{
+    type Output = Result<R, BoxError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project().kind.project() {
+            KindProj::Inner { future } => future.poll(cx).map_err(Into::into),
+            KindProj::Limited { error } => {
+                let error = error.take().expect("polled after completion");
+                Poll::Ready(Err(error.into()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future::{poll_fn, Ready};
+
+    use chrono::Duration as ChronoDuration;
+
+    use super::*;
+    use crate::timer::testing::{poll, MockTimer};
+    use crate::{Limiter, MockClock};
+
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<u32> for Echo {
+        type Response = u32;
+        type Error = Infallible;
+        type Future = Ready<Result<u32, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: u32) -> Self::Future {
+            std::future::ready(Ok(req))
+        }
+    }
+
+    fn call<S: Service<u32, Error = BoxError>>(
+        svc: &mut S,
+        req: u32,
+    ) -> Result<S::Response, BoxError> {
+        match poll(poll_fn(|cx| svc.poll_ready(cx))) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Err(e),
+            Poll::Pending => panic!("service not ready"),
+        }
+        match poll(svc.call(req)) {
+            Poll::Ready(res) => res,
+            Poll::Pending => panic!("response not ready"),
+        }
+    }
+
+    #[test]
+    fn should_wait_in_poll_ready() {
+        let clock = MockClock::new();
+        let limiter = Arc::new(Limiter::with_clock(
+            2,
+            ChronoDuration::seconds(1),
+            clock.clone(),
+        ));
+        let layer = RateLimitLayer::new(limiter).with_timer(MockTimer(clock.clone()));
+        let mut svc = layer.layer(Echo);
+
+        assert_eq!(call(&mut svc, 1).unwrap(), 1);
+        assert_eq!(call(&mut svc, 2).unwrap(), 2);
+
+        // The third request waits half a second for its unit
+        assert_eq!(call(&mut svc, 3).unwrap(), 3);
+        assert_eq!(clock.now(), 500_000_000);
+    }
+
+    #[test]
+    fn should_reject_over_the_limit() {
+        let clock = MockClock::new();
+        let limiter: Arc<dyn RateLimiter> = Arc::new(Limiter::with_clock(
+            1,
+            ChronoDuration::seconds(1),
+            clock.clone(),
+        ));
+        let mut svc = RateLimitLayer::new(limiter)
+            .with_timer(MockTimer(clock.clone()))
+            .reject()
+            .layer(Echo);
+
+        assert_eq!(call(&mut svc, 1).unwrap(), 1);
+        let err = call(&mut svc, 2).unwrap_err();
+        let err = err.downcast_ref::<RateLimitError>().unwrap();
+        assert_eq!(err.retry_after(), Duration::from_secs(1));
+        assert_eq!(clock.now(), 0);
+    }
+
+    struct Broken;
+
+    impl Service<u32> for Broken {
+        type Response = u32;
+        type Error = BoxError;
+        type Future = Ready<Result<u32, BoxError>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+            Poll::Ready(Err("broken".into()))
+        }
+
+        fn call(&mut self, req: u32) -> Self::Future {
+            std::future::ready(Ok(req))
+        }
+    }
+
+    #[test]
+    fn should_return_unused_permits() {
+        let clock = MockClock::new();
+        let limiter = Arc::new(Limiter::with_clock(
+            1,
+            ChronoDuration::seconds(1),
+            clock.clone(),
+        ));
+        let layer = RateLimitLayer::new(limiter.clone()).with_timer(MockTimer(clock.clone()));
+
+        // Dropped after becoming ready, as Buffer and balance do
+        let mut svc = layer.layer(Echo);
+        assert!(matches!(
+            poll(poll_fn(|cx| svc.poll_ready(cx))),
+            Poll::Ready(Ok(()))
+        ));
+        assert_eq!(limiter.snapshot().remaining, 0);
+        drop(svc);
+        assert_eq!(limiter.snapshot().remaining, 1);
+
+        // A failing inner service takes nothing
+        let mut svc = layer.layer(Broken);
+        assert!(call(&mut svc, 1).is_err());
+        assert_eq!(limiter.snapshot().remaining, 1);
+        assert_eq!(clock.now(), 0);
+    }
+
+    #[test]
+    fn should_limit_each_key() {
+        let clock = MockClock::new();
+        let keyed = Arc::new(KeyedLimiter::with_clock(
+            1,
+            ChronoDuration::seconds(1),
+            clock.clone(),
+        ));
+        let mut svc = RateLimitLayer::keyed(keyed, |req: &u32| req % 2)
+            .with_timer(MockTimer(clock.clone()))
+            .layer(Echo);
+
+        assert_eq!(call(&mut svc, 1).unwrap(), 1);
+        assert_eq!(call(&mut svc, 2).unwrap(), 2);
+        assert!(call(&mut svc, 3).is_err());
+        assert!(call(&mut svc, 4).is_err());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(call(&mut svc, 5).unwrap(), 5);
+    }
+}
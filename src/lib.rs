@@ -1,118 +1,718 @@
+#[cfg(feature = "axum")]
+pub mod axum;
+mod builder;
+mod clock;
+mod composite;
+mod decision;
+mod event;
+mod gcra;
+mod headers;
+mod keyed;
+#[cfg(feature = "tower")]
+mod layer;
+pub mod metrics;
+mod quota;
+mod reservation;
+mod state;
+mod stats;
+#[cfg(feature = "stream")]
+mod stream;
+mod timer;
+mod window;
+
+pub use builder::LimiterBuilder;
+pub use clock::{Clock, CoarseClock, MockClock, MonotonicClock, SystemClock};
+pub use composite::{check_all, Chain};
+pub use decision::{Decision, Snapshot};
+pub use event::Level;
+pub use gcra::GcraLimiter;
+pub use headers::RateLimitHeaders;
+pub use keyed::{Evictions, Janitor, KeyedLimiter};
+pub use quota::{QuotaLimiter, QuotaPeriod};
+pub use reservation::Reservation;
+pub use stats::Stats;
+pub use timer::Timer;
+pub use window::{SlidingWindowCounter, SlidingWindowLog};
+
+#[cfg(feature = "async-std")]
+pub use timer::AsyncStdTimer;
+#[cfg(any(feature = "tokio", feature = "async-std", feature = "futures-timer"))]
+pub use timer::DefaultTimer;
+#[cfg(feature = "futures-timer")]
+pub use timer::FuturesTimer;
+#[cfg(feature = "tokio")]
+pub use timer::TokioTimer;
+
+#[cfg(feature = "tower")]
+pub use layer::{
+    BoxError, Bucket, Keyed, RateLimit, RateLimitError, RateLimitLayer, ResponseFuture,
+};
+#[cfg(feature = "stream")]
+pub use stream::{RateLimitStream, StreamExt};
+
 use chrono::Duration;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+
+use event::Events;
+use state::{AtomicState, State};
+use stats::Counters;
+
+// RateLimiter is the behaviour shared by every limiter kind. It is object
+// safe, so limiters picked from configuration can be held as
+// Arc<dyn RateLimiter>.
+pub trait RateLimiter: Send + Sync {
+    // CheckN consumes n units if available and reports the full decision
+    fn check_n(&self, n: u64) -> Decision;
+
+    // UndoN reverts a CheckN(n) or LimitN(n) call
+    fn undo_n(&self, n: u64);
+
+    // Snapshot reports the current state without consuming anything
+    fn snapshot(&self) -> Snapshot;
+
+    // UpdateRate changes the number of units admitted per period, rejecting
+    // rates below 1
+    fn update_rate(&self, rate: i64) -> Result<(), LimiterError>;
+
+    // Check consumes a single unit and reports the full decision
+    fn check(&self) -> Decision {
+        self.check_n(1)
+    }
+
+    // LimitN returns true if consuming n units would exceed the rate
+    fn limit_n(&self, n: u64) -> bool {
+        self.check_n(n).is_limited()
+    }
+
+    // Limit returns true if rate was exceeded
+    fn limit(&self) -> bool {
+        self.limit_n(1)
+    }
+
+    // Undo reverts the last Limit() call
+    fn undo(&self) {
+        self.undo_n(1)
+    }
+
+    // Stats reports the decisions made so far, for limiters that count them
+    fn stats(&self) -> Option<Stats> {
+        None
+    }
+}
 
+// Limiter is a token bucket. Its allowance is tracked in units of one
+// nanosecond of refill per unit of rate, so the maximum allowance of burst
+// times the period in nanoseconds must fit in a u64: at most about 18.4
+// billion units per second, or 213,503 units per day. Rates and periods
+// beyond that are rejected by try_new, and the remaining arithmetic
+// saturates rather than overflows.
+//
 // Limiter instances are thread-safe.
-pub struct Limiter {
+pub struct Limiter<C = MonotonicClock> {
     pub rate: AtomicU64,
-    pub allowance: AtomicU64,
     pub max: AtomicU64,
-    pub unit: u64,
-    pub last_check: AtomicU64,
+    pub unit: AtomicU64,
+    state: AtomicState,
+    fixed_burst: AtomicBool,
+    reconfigure: Mutex<()>,
+    events: Events,
+    stats: Option<Box<Counters>>,
+    clock: C,
 }
 
 impl Limiter {
-    // New creates a new rate limiter instance
-    pub fn new(mut rate: i64, per: Duration) -> Limiter {
-        let mut nano = per.num_nanoseconds().unwrap() as u64;
-        if nano < 1 {
-            nano = Duration::seconds(1).num_nanoseconds().unwrap() as u64;
-        }
+    // New creates a new rate limiter instance. Rates below one are raised to
+    // one and empty or negative periods replaced with one second; use TryNew
+    // to have them reported instead.
+    pub fn new(rate: i64, per: Duration) -> Limiter {
+        Limiter::with_clock(rate, per, MonotonicClock::new())
+    }
 
-        if rate < 1 {
-            rate = 1;
-        }
+    // TryNew is like New but reports rates below one, empty or negative
+    // periods and configurations whose maximum allowance cannot be
+    // represented, where New silently adjusts or panics
+    pub fn try_new(rate: i64, per: Duration) -> Result<Limiter, LimiterError> {
+        Limiter::builder().rate(rate).per(per).try_build()
+    }
+
+    // Builder configures a limiter with a burst independent of its rate or
+    // starting empty
+    pub fn builder() -> LimiterBuilder {
+        LimiterBuilder::default()
+    }
+}
 
-        let rate = rate as u64;
+impl<C: Clock> Limiter<C> {
+    // WithClock creates a new rate limiter instance reading time from clock
+    pub fn with_clock(rate: i64, per: Duration, clock: C) -> Limiter<C> {
+        Limiter::builder().rate(rate).per(per).clock(clock).build()
+    }
 
+    pub(crate) fn from_parts(
+        rate: u64,
+        unit: u64,
+        max: u64,
+        allowance: u64,
+        fixed_burst: bool,
+        events: Events,
+        clock: C,
+    ) -> Limiter<C> {
         Limiter {
             rate: AtomicU64::new(rate),
-            allowance: AtomicU64::new(rate * nano),
-            max: AtomicU64::new(rate * nano),
-            unit: nano,
-            last_check: AtomicU64::new(unix_nano()),
+            max: AtomicU64::new(max),
+            unit: AtomicU64::new(unit),
+            state: AtomicState::new(State {
+                allowance,
+                last_check: clock.now(),
+            }),
+            fixed_burst: AtomicBool::new(fixed_burst),
+            reconfigure: Mutex::new(()),
+            events,
+            stats: None,
+            clock,
         }
     }
 
-    // Update rate updates the allowed rate
-    pub fn update_rate(&self, rate: i64) {
-        let rate = rate as u64;
+    // Name returns the name the limiter reports its decisions under
+    pub fn name(&self) -> Option<&str> {
+        self.events.name.as_deref()
+    }
+
+    // Key returns the key label of a bucket created by a KeyedLimiter
+    pub fn key(&self) -> Option<&str> {
+        self.events.key.as_deref()
+    }
+
+    // Clock returns the clock the limiter reads time from
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    // Update rate updates the allowed rate, rescaling the current allowance
+    // proportionally. The burst follows the rate unless it was set explicitly.
+    pub fn update_rate(&self, rate: i64) -> Result<(), LimiterError> {
+        self.set_rate(rate, Rescale::Proportional)
+    }
 
+    // SetRate updates the allowed rate, rescaling the current allowance
+    // according to policy
+    pub fn set_rate(&self, rate: i64, policy: Rescale) -> Result<(), LimiterError> {
+        self.reconfigure(Some(rate), None, None, policy)
+    }
+
+    // SetPeriod updates the period the rate applies to, keeping the rate and
+    // burst in units and rescaling the current allowance according to policy
+    pub fn set_period(&self, per: Duration, policy: Rescale) -> Result<(), LimiterError> {
+        self.reconfigure(None, Some(per), None, policy)
+    }
+
+    // SetBurst updates the number of units the bucket holds at most, which
+    // stops it from following later rate changes
+    pub fn set_burst(&self, burst: u64, policy: Rescale) -> Result<(), LimiterError> {
+        self.reconfigure(None, None, Some(burst), policy)
+    }
+
+    // reconfigure validates and applies a configuration change. Changes are
+    // serialized with each other; a check racing with one may still see the
+    // old configuration, but never more allowance than either configuration
+    // permits.
+    fn reconfigure(
+        &self,
+        rate: Option<i64>,
+        per: Option<Duration>,
+        burst: Option<u64>,
+        policy: Rescale,
+    ) -> Result<(), LimiterError> {
+        let _guard = self.reconfigure.lock().unwrap();
+
+        let old_rate = self.rate.load(Ordering::Relaxed);
+        let old_unit = self.unit.load(Ordering::Relaxed);
+        let old_max = self.max.load(Ordering::Relaxed);
+
+        let rate = match rate {
+            Some(rate) if rate < 1 => return Err(LimiterError::ZeroRate),
+            Some(rate) => rate as u64,
+            None => old_rate,
+        };
+        let unit = match per {
+            Some(per) => builder::nanos(per)?,
+            None => old_unit,
+        };
+
+        let fixed = burst.is_some() || self.fixed_burst.load(Ordering::Relaxed);
+        let burst = match burst {
+            Some(0) => return Err(LimiterError::ZeroBurst),
+            Some(burst) => burst,
+            None if fixed => old_max / old_unit,
+            None => rate,
+        };
+        let max = burst.checked_mul(unit).ok_or(LimiterError::Overflow)?;
+
+        // Shrink the maximum before rescaling and grow it afterwards, so the
+        // allowance never exceeds the maximum of the moment
+        if max < old_max {
+            self.max.store(max, Ordering::Relaxed);
+        }
+
+        // Settle the allowance under the old configuration and rescale it
+        let now = self.clock.now();
+        self.state.update(|s| {
+            let s = s.refill(now, old_rate, old_max);
+            let allowance = match policy {
+                Rescale::Proportional => {
+                    (s.allowance as u128 * max as u128 / old_max as u128) as u64
+                }
+                Rescale::KeepTokens => {
+                    ((s.allowance as u128 * unit as u128 / old_unit as u128) as u64).min(max)
+                }
+                Rescale::Full => max,
+                Rescale::Empty => 0,
+            };
+
+            (State { allowance, ..s }, ())
+        });
+
+        self.unit.store(unit, Ordering::Relaxed);
         self.rate.store(rate, Ordering::Relaxed);
-        self.max.store(rate * self.unit, Ordering::Relaxed);
+        self.max.store(max, Ordering::Relaxed);
+        self.fixed_burst.store(fixed, Ordering::Relaxed);
+
+        Ok(())
     }
 
     // Limit returns true if rate was exceeded
     pub fn limit(&self) -> bool {
-        let rate = self.rate.load(Ordering::Relaxed);
-        // println!("rate is {rate}");
-        // println!("unit is {}", self.unit);
+        self.limit_n(1)
+    }
 
-        // Calculate the number of ns that have passed since our last call
-        let now = unix_nano();
-        // println!("now is {now}");
+    // LimitN returns true if consuming n units would exceed the rate. Costs
+    // larger than the bucket capacity are always limited.
+    pub fn limit_n(&self, n: u64) -> bool {
+        self.try_acquire(n).is_err()
+    }
+
+    // TryAcquire atomically deducts cost units from the allowance, reporting
+    // why the request was rejected otherwise.
+    pub fn try_acquire(&self, cost: u64) -> Result<(), AcquireError> {
+        let rate = self.rate.load(Ordering::Relaxed);
+        let max = self.max.load(Ordering::Relaxed);
+        let now = self.clock.now();
+        let need = match self.units(cost, max) {
+            Ok(need) => need,
+            Err(err) => {
+                let curr = self.peek(now, rate, max).allowance;
+                let need = cost.saturating_mul(self.unit());
+                self.observe(false, cost, curr, need.saturating_sub(curr));
+                return Err(err);
+            }
+        };
 
-        let passed = now - self.last_check.swap(now, Ordering::Relaxed);
-        // println!("passed is {passed}");
+        match self.take(need, now, rate, max) {
+            Ok(s) => {
+                self.observe(true, cost, s.allowance, 0);
+                Ok(())
+            }
+            Err(s) => {
+                self.observe(false, cost, s.allowance, need - s.allowance);
+                Err(AcquireError::RateLimited)
+            }
+        }
+    }
 
-        // Add them to our allowance
-        let mut prev = self.allowance.load(Ordering::Relaxed);
-        // println!("prev is {prev}");
+    // Check consumes a single unit and reports the full decision
+    pub fn check(&self) -> Decision {
+        self.check_n(1)
+    }
 
-        let mut curr = prev + (passed * rate);
+    // CheckN consumes cost units if available and reports the full decision,
+    // including how long to wait before retrying.
+    pub fn check_n(&self, cost: u64) -> Decision {
+        let rate = self.rate.load(Ordering::Relaxed);
+        let unit = self.unit.load(Ordering::Relaxed);
+        let max = self.max.load(Ordering::Relaxed);
+        let need = self.units(cost, max);
+        let now = self.clock.now();
 
-        loop {
-            match self.allowance.compare_exchange_weak(
-                prev,
-                curr,
-                Ordering::Relaxed,
-                Ordering::Relaxed,
-            ) {
-                Ok(_) => break,
-                Err(x) => prev = x,
+        let (allowed, s, deficit) = match need {
+            Ok(need) => match self.take(need, now, rate, max) {
+                Ok(s) => (true, s, 0),
+                Err(s) => (false, s, need - s.allowance),
+            },
+            Err(_) => {
+                let s = self.refill(now, rate, max);
+                (
+                    false,
+                    s,
+                    cost.saturating_mul(unit).saturating_sub(s.allowance),
+                )
             }
+        };
+        let curr = s.allowance;
+        self.observe(allowed, cost, curr, deficit);
 
-            curr = prev + (passed * rate);
-        }
+        // Nothing refills until outstanding reservations are paid off
+        let debt = s.debt(now);
+        let retry_after = match need {
+            Ok(need) => decision::nanos_until(need.saturating_sub(curr), rate),
+            Err(_) => StdDuration::MAX,
+        };
+        let reset_after = decision::nanos_until(max.saturating_sub(curr), rate);
 
-        // println!("curr is {curr}");
+        Decision {
+            allowed,
+            remaining: curr / unit,
+            retry_after: debt.saturating_add(retry_after),
+            reset_after: debt.saturating_add(reset_after),
+        }
+    }
 
-        // Ensure our allowance is not over maximum
+    // Snapshot reports the current state without consuming anything
+    pub fn snapshot(&self) -> Snapshot {
+        let rate = self.rate.load(Ordering::Relaxed);
+        let unit = self.unit.load(Ordering::Relaxed);
         let max = self.max.load(Ordering::Relaxed);
-        // println!("max is {max}");
+        let now = self.clock.now();
+        let s = self.peek(now, rate, max);
 
-        if curr >= max {
-            self.allowance.fetch_sub(curr - max, Ordering::Relaxed);
-            curr = max;
+        Snapshot {
+            capacity: max / unit,
+            remaining: s.allowance / unit,
+            rate,
+            period: StdDuration::from_nanos(unit),
+            reset_after: s
+                .debt(now)
+                .saturating_add(decision::nanos_until(max.saturating_sub(s.allowance), rate)),
         }
+    }
+
+    // IsFull returns true if the bucket has refilled to its maximum allowance,
+    // making it indistinguishable from a freshly created one
+    pub fn is_full(&self) -> bool {
+        let rate = self.rate.load(Ordering::Relaxed);
+        let max = self.max.load(Ordering::Relaxed);
+        self.peek(self.clock.now(), rate, max).allowance >= max
+    }
+
+    // Wait blocks the calling thread until a single unit is available
+    pub fn wait(&self) -> Result<(), AcquireError> {
+        self.wait_n(1)
+    }
+
+    // WaitN blocks the calling thread until cost units are available, sleeping
+    // for exactly as long as the allowance deficit takes to refill.
+    pub fn wait_n(&self, cost: u64) -> Result<(), AcquireError> {
+        self.wait_until(cost, None)
+    }
+
+    // WaitTimeout is like WaitN but gives up as soon as it is clear that cost
+    // units cannot be acquired within timeout.
+    pub fn wait_timeout(&self, cost: u64, timeout: StdDuration) -> Result<(), AcquireError> {
+        self.wait_until(cost, Some(timeout))
+    }
 
-        // If our allowance is less than one unit, rate-limit!
-        if curr < self.unit {
-            println!("rate-limit!!!!");
-            return true;
+    fn wait_until(&self, cost: u64, timeout: Option<StdDuration>) -> Result<(), AcquireError> {
+        let start = self.clock.now();
+
+        loop {
+            let d = self.check_n(cost);
+            if d.allowed {
+                return Ok(());
+            }
+
+            if d.retry_after == StdDuration::MAX {
+                return Err(AcquireError::InsufficientCapacity {
+                    cost,
+                    capacity: self.max.load(Ordering::Relaxed) / self.unit.load(Ordering::Relaxed),
+                });
+            }
+
+            if let Some(timeout) = timeout {
+                let elapsed = StdDuration::from_nanos(self.clock.now().saturating_sub(start));
+                if d.retry_after > timeout.saturating_sub(elapsed) {
+                    return Err(AcquireError::Timeout);
+                }
+            }
+
+            self.clock.sleep(d.retry_after);
         }
+    }
+
+    // Acquire waits asynchronously until cost units are available, sleeping
+    // on the runtime selected by the enabled cargo features.
+    #[cfg(any(feature = "tokio", feature = "async-std", feature = "futures-timer"))]
+    pub async fn acquire(&self, cost: u64) -> Result<(), AcquireError> {
+        self.acquire_with(&DefaultTimer::default(), cost).await
+    }
 
-        // Not limited, subtract a unit
-        self.allowance.fetch_sub(self.unit, Ordering::Relaxed);
+    // AcquireWith waits asynchronously until cost units are available,
+    // sleeping on timer. Allowance is only ever deducted by the synchronous
+    // check between sleeps, so dropping the future never leaks any.
+    pub async fn acquire_with<T: Timer>(&self, timer: &T, cost: u64) -> Result<(), AcquireError> {
+        loop {
+            let d = self.check_n(cost);
+            if d.allowed {
+                return Ok(());
+            }
+
+            if d.retry_after == StdDuration::MAX {
+                return Err(AcquireError::InsufficientCapacity {
+                    cost,
+                    capacity: self.max.load(Ordering::Relaxed) / self.unit.load(Ordering::Relaxed),
+                });
+            }
 
-        false
+            timer.delay(d.retry_after).await;
+        }
     }
 
     // Undo reverts the last Limit() call, returning consumed allowance
     pub fn undo(&self) {
+        self.undo_n(1)
+    }
+
+    // UndoN reverts a LimitN(n) call, returning n units of allowance
+    pub fn undo_n(&self, n: u64) {
+        if let Some(stats) = &self.stats {
+            stats.undo();
+        }
+        self.credit(n.saturating_mul(self.unit.load(Ordering::Relaxed)))
+    }
+
+    // Stats reports the decisions made since the limiter was created or its
+    // statistics last reset, if it was built to track them
+    pub fn stats(&self) -> Option<Stats> {
+        self.stats.as_ref().map(|stats| stats.snapshot())
+    }
+
+    // ResetStats starts counting decisions afresh
+    pub fn reset_stats(&self) {
+        if let Some(stats) = &self.stats {
+            stats.reset();
+        }
+    }
+
+    // Reserve takes cost units now, borrowing against future refills if the
+    // allowance does not cover them. The returned reservation reports how long
+    // to wait before acting and refunds the units unless committed.
+    pub fn reserve(&self, cost: u64) -> Result<Reservation<'_, C>, AcquireError> {
+        let rate = self.rate.load(Ordering::Relaxed);
+        let max = self.max.load(Ordering::Relaxed);
+        let need = self.units(cost, max)?;
+
+        let now = self.clock.now();
+        let (ready_at, curr) = self.state.update(|s| {
+            let s = s.refill(now, rate, max);
+
+            // Push the last check into the future by the time it takes to
+            // refill the shortfall, so no allowance accrues until the debt is
+            // paid off
+            let deficit = need.saturating_sub(s.allowance);
+            if deficit == 0 {
+                let next = State {
+                    allowance: s.allowance - need,
+                    ..s
+                };
+                return (next, (now, next.allowance));
+            }
+
+            let last_check = s.last_check.saturating_add(deficit.div_ceil(rate));
+            let next = State {
+                allowance: 0,
+                last_check,
+            };
+            (next, (last_check, 0))
+        });
+
+        // The whole outstanding debt, including earlier reservations
+        let debt = (ready_at - now).saturating_mul(rate);
+        self.observe(true, cost, curr, debt);
+
+        Ok(Reservation::new(self, cost, ready_at))
+    }
+
+    // credit returns amount of allowance, first paying off any debt taken on
+    // by reservations and never exceeding the maximum allowance.
+    fn credit(&self, amount: u64) {
+        let rate = self.rate.load(Ordering::Relaxed);
         let max = self.max.load(Ordering::Relaxed);
-        let prev = self.allowance.fetch_add(self.unit, Ordering::Relaxed);
+        let now = self.clock.now();
+
+        self.state.update(|s| {
+            let mut s = s.refill(now, rate, max);
+            let mut amount = amount;
+
+            if s.last_check > now {
+                let paid = (s.last_check - now).min(amount / rate);
+                s.last_check -= paid;
+                amount -= paid * rate;
+            }
 
-        if prev >= max {
-            self.allowance.fetch_add(max - prev, Ordering::Relaxed);
+            s.allowance = s.allowance.saturating_add(amount).min(max);
+            (s, ())
+        })
+    }
+
+    // peek returns the state as of now under rate and max without updating
+    // it. Callers load the configuration once and pass it to every step, so
+    // a concurrent reconfigure can't mix two configurations in one result.
+    fn peek(&self, now: u64, rate: u64, max: u64) -> State {
+        self.state.load().refill(now, rate, max)
+    }
+
+    // take deducts need from the allowance if it covers it, returning the
+    // state left afterwards, or the current state on rejection. The refill
+    // and the deduction are a single atomic step, so concurrent callers can
+    // never spend the same allowance twice.
+    fn take(&self, need: u64, now: u64, rate: u64, max: u64) -> Result<State, State> {
+        self.state.update(|s| {
+            let s = s.refill(now, rate, max);
+
+            // If our allowance is less than the cost, rate-limit!
+            if s.allowance < need {
+                return (s, Err(s));
+            }
+
+            // Not limited, subtract the cost
+            let next = State {
+                allowance: s.allowance - need,
+                ..s
+            };
+            (next, Ok(next))
+        })
+    }
+
+    // refill credits the time passed since the last check to the allowance
+    // and returns the resulting state
+    fn refill(&self, now: u64, rate: u64, max: u64) -> State {
+        self.state.update(|s| {
+            let s = s.refill(now, rate, max);
+            (s, s)
+        })
+    }
+
+    // observe reports a decision on cost units, leaving an allowance of curr
+    // and falling short by deficit, to the event log and statistics
+    fn observe(&self, allowed: bool, cost: u64, curr: u64, deficit: u64) {
+        let unit = self.unit();
+        self.events.emit(allowed, cost, curr / unit);
+
+        if let Some(stats) = &self.stats {
+            stats.record(allowed, cost, deficit.div_ceil(unit));
+        }
+    }
+
+    fn unit(&self) -> u64 {
+        self.unit.load(Ordering::Relaxed)
+    }
+
+    // units converts a cost to allowance, failing if max can never cover it
+    fn units(&self, cost: u64, max: u64) -> Result<u64, AcquireError> {
+        let unit = self.unit.load(Ordering::Relaxed);
+        match cost.checked_mul(unit) {
+            Some(need) if need <= max => Ok(need),
+            _ => Err(AcquireError::InsufficientCapacity {
+                cost,
+                capacity: max / unit,
+            }),
+        }
+    }
+}
+
+impl<C: Clock + Send + Sync> RateLimiter for Limiter<C> {
+    fn check_n(&self, n: u64) -> Decision {
+        Limiter::check_n(self, n)
+    }
+
+    fn undo_n(&self, n: u64) {
+        Limiter::undo_n(self, n)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Limiter::snapshot(self)
+    }
+
+    fn update_rate(&self, rate: i64) -> Result<(), LimiterError> {
+        Limiter::update_rate(self, rate)
+    }
+
+    fn stats(&self) -> Option<Stats> {
+        Limiter::stats(self)
+    }
+}
+
+// AcquireError describes why a weighted acquisition was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcquireError {
+    // The allowance does not currently cover the requested cost
+    RateLimited,
+    // The requested cost exceeds what the bucket can ever hold
+    InsufficientCapacity { cost: u64, capacity: u64 },
+    // The cost could not be acquired before the deadline
+    Timeout,
+}
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcquireError::RateLimited => write!(f, "rate limit exceeded"),
+            AcquireError::InsufficientCapacity { cost, capacity } => {
+                write!(f, "cost {cost} exceeds limiter capacity of {capacity}")
+            }
+            AcquireError::Timeout => write!(f, "deadline exceeded waiting for allowance"),
         }
     }
 }
 
-// now as unix nanoseconds
-fn unix_nano() -> u64 {
-    chrono::Utc::now().timestamp_nanos() as u64
+impl std::error::Error for AcquireError {}
+
+// Rescale decides what happens to the current allowance when a limiter's
+// configuration changes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rescale {
+    // Keep the same fraction of the capacity
+    #[default]
+    Proportional,
+    // Keep the same number of whole and partial units, up to the new capacity
+    KeepTokens,
+    // Start over with a full bucket
+    Full,
+    // Start over with an empty bucket
+    Empty,
+}
+
+// LimiterError describes a limiter configuration that cannot be honoured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimiterError {
+    // The rate is zero or negative
+    ZeroRate,
+    // The burst is zero
+    ZeroBurst,
+    // The period is zero or negative
+    InvalidPeriod,
+    // The maximum allowance, burst times the period in nanoseconds, or the
+    // period itself does not fit in 64 bits
+    Overflow,
+    // The limiter has no single rate to update, like a Chain
+    Unsupported,
 }
 
+impl fmt::Display for LimiterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimiterError::ZeroRate => write!(f, "rate must be at least 1"),
+            LimiterError::ZeroBurst => write!(f, "burst must be at least 1"),
+            LimiterError::InvalidPeriod => write!(f, "period must be positive"),
+            LimiterError::Overflow => write!(f, "limiter configuration overflows 64 bits"),
+            LimiterError::Unsupported => write!(f, "limiter has no single rate to update"),
+        }
+    }
+}
+
+impl std::error::Error for LimiterError {}
+
 #[cfg(test)]
 mod tests {
     use std::thread::sleep;
@@ -120,6 +720,7 @@ mod tests {
     use approx::relative_eq;
 
     use super::*;
+    use crate::timer::testing::{block_on, poll, MockTimer};
 
     #[test]
     fn should_limit_low_rates() {
@@ -134,6 +735,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::unnecessary_cast)]
     fn should_limit_high_rates() {
         let mut c = 0;
         let l = Limiter::new(1000, chrono::Duration::seconds(1));
@@ -146,6 +748,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn should_increase_allowances() {
         let n = 25;
         let l = Limiter::new(n, Duration::milliseconds(50));
@@ -159,4 +762,600 @@ mod tests {
         sleep(Duration::milliseconds(10).to_std().unwrap());
         assert_eq!(l.limit(), false);
     }
+
+    #[test]
+    fn should_limit_weighted_costs() {
+        let l = Limiter::new(10, Duration::minutes(1));
+
+        assert!(!l.limit_n(4));
+        assert!(!l.limit_n(4));
+        assert!(l.limit_n(4));
+        assert!(!l.limit_n(2));
+        assert!(l.limit());
+
+        l.undo_n(3);
+        assert_eq!(l.try_acquire(3), Ok(()));
+        assert_eq!(l.try_acquire(1), Err(AcquireError::RateLimited));
+    }
+
+    #[test]
+    fn should_reject_costs_over_capacity() {
+        let l = Limiter::new(10, Duration::minutes(1));
+
+        assert_eq!(
+            l.try_acquire(11),
+            Err(AcquireError::InsufficientCapacity {
+                cost: 11,
+                capacity: 10
+            })
+        );
+        assert!(!l.limit_n(10));
+    }
+
+    #[test]
+    fn should_not_refund_beyond_max() {
+        let l = Limiter::new(5, Duration::minutes(1));
+
+        l.undo_n(3);
+        assert!(!l.limit_n(5));
+        assert!(l.limit());
+    }
+
+    #[test]
+    fn should_report_decisions() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(4, Duration::seconds(4), clock.clone());
+
+        let d = l.check_n(3);
+        assert!(d.allowed);
+        assert_eq!(d.remaining, 1);
+        assert_eq!(d.retry_after, StdDuration::from_secs(2));
+        assert_eq!(d.reset_after, StdDuration::from_secs(3));
+
+        let d = l.check_n(2);
+        assert!(d.is_limited());
+        assert_eq!(d.remaining, 1);
+        assert_eq!(d.retry_after, StdDuration::from_secs(1));
+
+        clock.advance(StdDuration::from_millis(500));
+        let d = l.check_n(2);
+        assert!(d.is_limited());
+        assert_eq!(d.retry_after, StdDuration::from_millis(500));
+        assert_eq!(d.reset_after, StdDuration::from_millis(2500));
+
+        let d = l.check_n(5);
+        assert!(d.is_limited());
+        assert_eq!(d.retry_after, StdDuration::MAX);
+    }
+
+    #[test]
+    fn should_refill_from_clock() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(10, Duration::seconds(1), clock.clone());
+
+        assert!(!l.limit_n(10));
+        assert!(l.limit());
+
+        clock.advance(StdDuration::from_millis(250));
+        assert!(!l.limit_n(2));
+        assert!(l.limit());
+
+        clock.advance(StdDuration::from_secs(5));
+        assert!(!l.limit_n(10));
+        assert!(l.limit());
+    }
+
+    #[test]
+    fn should_survive_clock_going_backwards() {
+        let clock = MockClock::new();
+        clock.set(StdDuration::from_secs(10));
+        let l = Limiter::with_clock(10, Duration::seconds(1), clock.clone());
+
+        assert!(!l.limit_n(10));
+
+        clock.set(StdDuration::from_secs(5));
+        assert!(l.limit());
+
+        clock.set(StdDuration::from_millis(10_100));
+        assert!(!l.limit());
+        assert!(l.limit());
+    }
+
+    #[test]
+    fn should_wait_for_allowance() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(10, Duration::seconds(1), clock.clone());
+
+        assert!(!l.limit_n(10));
+        assert_eq!(l.wait_n(3), Ok(()));
+        assert_eq!(clock.now(), 300_000_000);
+
+        assert_eq!(l.wait(), Ok(()));
+        assert_eq!(clock.now(), 400_000_000);
+
+        assert_eq!(
+            l.wait_n(11),
+            Err(AcquireError::InsufficientCapacity {
+                cost: 11,
+                capacity: 10
+            })
+        );
+    }
+
+    #[test]
+    fn should_fail_waits_that_miss_the_deadline() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(10, Duration::seconds(1), clock.clone());
+
+        assert!(!l.limit_n(10));
+        assert_eq!(
+            l.wait_timeout(5, StdDuration::from_millis(499)),
+            Err(AcquireError::Timeout)
+        );
+        assert_eq!(clock.now(), 0);
+
+        assert_eq!(l.wait_timeout(5, StdDuration::from_millis(500)), Ok(()));
+        assert_eq!(clock.now(), 500_000_000);
+    }
+
+    #[test]
+    fn should_park_real_threads() {
+        let l = Limiter::new(100, Duration::seconds(1));
+        assert!(!l.limit_n(100));
+
+        let start = std::time::Instant::now();
+        assert_eq!(l.wait_n(2), Ok(()));
+        assert!(start.elapsed() >= StdDuration::from_millis(19));
+    }
+
+    struct PendingTimer;
+
+    impl Timer for PendingTimer {
+        type Delay = std::future::Pending<()>;
+
+        fn delay(&self, _: StdDuration) -> Self::Delay {
+            std::future::pending()
+        }
+    }
+
+    #[test]
+    fn should_acquire_asynchronously() {
+        let clock = MockClock::new();
+        let timer = MockTimer(clock.clone());
+        let l = Limiter::with_clock(10, Duration::seconds(1), clock.clone());
+
+        assert!(!l.limit_n(10));
+        assert_eq!(block_on(l.acquire_with(&timer, 4)), Ok(()));
+        assert_eq!(clock.now(), 400_000_000);
+        assert!(l.limit());
+    }
+
+    #[test]
+    fn should_not_leak_allowance_when_cancelled() {
+        let l = Limiter::new(10, Duration::minutes(1));
+        assert!(!l.limit_n(9));
+
+        let timer = PendingTimer;
+        assert!(poll(l.acquire_with(&timer, 5)).is_pending());
+
+        assert!(!l.limit());
+    }
+
+    #[test]
+    fn should_reserve_available_allowance() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(10, Duration::seconds(1), clock.clone());
+
+        let r = l.reserve(4).unwrap();
+        assert_eq!(r.delay(), StdDuration::ZERO);
+        r.commit();
+        assert!(!l.limit_n(6));
+        assert!(l.limit());
+    }
+
+    #[test]
+    fn should_borrow_against_future_allowance() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(10, Duration::seconds(1), clock.clone());
+        assert!(!l.limit_n(8));
+
+        let r = l.reserve(5).unwrap();
+        assert_eq!(r.delay(), StdDuration::from_millis(300));
+        r.commit();
+
+        clock.advance(StdDuration::from_millis(300));
+        assert!(l.limit());
+
+        clock.advance(StdDuration::from_millis(100));
+        assert!(!l.limit());
+        assert!(l.limit());
+    }
+
+    #[test]
+    fn should_include_reservation_debt_in_retry_times() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(10, Duration::seconds(1), clock.clone());
+
+        l.reserve(10).unwrap().commit();
+        l.reserve(10).unwrap().commit();
+
+        let d = l.check();
+        assert!(d.is_limited());
+        assert_eq!(d.retry_after, StdDuration::from_millis(1100));
+        assert_eq!(d.reset_after, StdDuration::from_secs(2));
+        assert_eq!(l.snapshot().reset_after, StdDuration::from_secs(2));
+
+        clock.advance(StdDuration::from_millis(1099));
+        assert!(l.limit());
+        clock.advance(StdDuration::from_millis(1));
+        assert!(!l.limit());
+    }
+
+    #[test]
+    fn should_refund_cancelled_reservations() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(10, Duration::seconds(1), clock.clone());
+        assert!(!l.limit_n(8));
+
+        let r = l.reserve(5).unwrap();
+        clock.advance(StdDuration::from_millis(100));
+        r.cancel();
+
+        // Without the reservation we would be at 3 units by now
+        assert!(!l.limit_n(3));
+        assert!(l.limit());
+    }
+
+    #[test]
+    fn should_cancel_reservations_on_drop() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(10, Duration::seconds(1), clock.clone());
+
+        {
+            let r = l.reserve(10).unwrap();
+            assert_eq!(r.cost(), 10);
+            assert!(l.limit());
+        }
+
+        assert!(!l.limit_n(10));
+        assert!(l.reserve(11).is_err());
+    }
+
+    #[test]
+    fn should_work_as_trait_object() {
+        let clock = MockClock::new();
+        let l: std::sync::Arc<dyn RateLimiter> =
+            std::sync::Arc::new(Limiter::with_clock(10, Duration::seconds(1), clock.clone()));
+
+        assert!(l.check_n(4).allowed);
+        assert_eq!(
+            l.snapshot(),
+            Snapshot {
+                capacity: 10,
+                remaining: 6,
+                rate: 10,
+                period: StdDuration::from_secs(1),
+                reset_after: StdDuration::from_millis(400),
+            }
+        );
+
+        l.update_rate(20).unwrap();
+        assert_eq!(l.snapshot().capacity, 20);
+        l.undo_n(4);
+        assert!(!l.limit_n(10));
+    }
+
+    #[test]
+    fn should_reject_unrepresentable_configurations() {
+        assert_eq!(
+            Limiter::try_new(20_000_000_000, Duration::seconds(1)).err(),
+            Some(LimiterError::Overflow)
+        );
+        assert_eq!(
+            Limiter::try_new(1000, Duration::days(365)).err(),
+            Some(LimiterError::Overflow)
+        );
+        assert_eq!(
+            Limiter::try_new(1, Duration::days(365 * 1000)).err(),
+            Some(LimiterError::Overflow)
+        );
+        assert!(Limiter::try_new(18_000_000_000, Duration::seconds(1)).is_ok());
+        assert!(Limiter::try_new(213_503, Duration::days(1)).is_ok());
+    }
+
+    #[test]
+    fn should_saturate_at_extremes() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(18_000_000_000, Duration::seconds(1), clock.clone());
+
+        assert!(!l.limit_n(18_000_000_000));
+        assert_eq!(
+            l.try_acquire(u64::MAX),
+            Err(AcquireError::InsufficientCapacity {
+                cost: u64::MAX,
+                capacity: 18_000_000_000
+            })
+        );
+        assert!(l.reserve(u64::MAX).is_err());
+        l.undo_n(u64::MAX);
+
+        // A very long idle time refills to max instead of wrapping around
+        clock.set(StdDuration::from_secs(500 * 365 * 86400));
+        assert_eq!(l.snapshot().remaining, 18_000_000_000);
+        assert!(!l.limit_n(18_000_000_000));
+        assert!(l.limit());
+    }
+
+    #[test]
+    fn should_report_invalid_configurations() {
+        assert_eq!(
+            Limiter::try_new(0, Duration::seconds(1)).err(),
+            Some(LimiterError::ZeroRate)
+        );
+        assert_eq!(
+            Limiter::try_new(-5, Duration::seconds(1)).err(),
+            Some(LimiterError::ZeroRate)
+        );
+        assert_eq!(
+            Limiter::try_new(10, Duration::zero()).err(),
+            Some(LimiterError::InvalidPeriod)
+        );
+        assert_eq!(
+            Limiter::try_new(10, Duration::seconds(-1)).err(),
+            Some(LimiterError::InvalidPeriod)
+        );
+        assert_eq!(
+            Limiter::try_new(1, Duration::days(365 * 300)).err(),
+            Some(LimiterError::Overflow)
+        );
+
+        // New keeps adjusting them
+        let l = Limiter::new(0, Duration::zero());
+        assert_eq!(l.snapshot().capacity, 1);
+        assert_eq!(l.snapshot().period, StdDuration::from_secs(1));
+    }
+
+    #[test]
+    fn should_rescale_allowance_proportionally() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(10, Duration::seconds(1), clock.clone());
+
+        // Half full stays half full
+        assert!(!l.limit_n(5));
+        l.update_rate(100).unwrap();
+        assert_eq!(l.snapshot().remaining, 50);
+        l.update_rate(4).unwrap();
+        assert_eq!(l.snapshot().remaining, 2);
+
+        assert_eq!(l.update_rate(0), Err(LimiterError::ZeroRate));
+        assert_eq!(l.update_rate(-1), Err(LimiterError::ZeroRate));
+        assert_eq!(l.snapshot().rate, 4);
+    }
+
+    #[test]
+    fn should_rescale_allowance_by_policy() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(10, Duration::seconds(1), clock.clone());
+
+        assert!(!l.limit_n(7));
+        l.set_rate(100, Rescale::KeepTokens).unwrap();
+        assert_eq!(l.snapshot().remaining, 3);
+        l.set_rate(2, Rescale::KeepTokens).unwrap();
+        assert_eq!(l.snapshot().remaining, 2);
+
+        l.set_rate(10, Rescale::Empty).unwrap();
+        assert!(l.limit());
+        l.set_rate(10, Rescale::Full).unwrap();
+        assert!(!l.limit_n(10));
+    }
+
+    #[test]
+    fn should_change_period_and_burst() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(10, Duration::seconds(1), clock.clone());
+
+        // Ten per minute refills a unit every six seconds
+        assert!(!l.limit_n(10));
+        l.set_period(Duration::minutes(1), Rescale::Proportional)
+            .unwrap();
+        clock.advance(StdDuration::from_secs(5));
+        assert!(l.limit());
+        clock.advance(StdDuration::from_secs(1));
+        assert!(!l.limit());
+
+        // An explicit burst no longer follows the rate
+        l.set_burst(40, Rescale::Full).unwrap();
+        l.update_rate(20).unwrap();
+        let s = l.snapshot();
+        assert_eq!((s.capacity, s.remaining, s.rate), (40, 40, 20));
+        assert_eq!(s.period, StdDuration::from_secs(60));
+
+        assert_eq!(
+            l.set_period(Duration::zero(), Rescale::Full),
+            Err(LimiterError::InvalidPeriod)
+        );
+        assert_eq!(l.set_burst(0, Rescale::Full), Err(LimiterError::ZeroBurst));
+        assert_eq!(
+            l.set_period(Duration::days(365 * 300), Rescale::Full),
+            Err(LimiterError::Overflow)
+        );
+        assert_eq!(l.snapshot().capacity, 40);
+    }
+
+    #[test]
+    fn should_update_rate_during_concurrent_limits() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(1000, Duration::seconds(1), clock);
+
+        let admitted = std::thread::scope(|s| {
+            let workers: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        (0..1000)
+                            .filter(|_| {
+                                let snapshot = l.snapshot();
+                                assert!(snapshot.remaining <= snapshot.capacity);
+                                !l.limit()
+                            })
+                            .count()
+                    })
+                })
+                .collect();
+
+            for rate in [500, 2000, 100, 1000] {
+                l.update_rate(rate).unwrap();
+            }
+
+            workers
+                .into_iter()
+                .map(|w| w.join().unwrap())
+                .sum::<usize>()
+        });
+
+        // Rescaling never hands out more than the largest capacity
+        assert!(admitted <= 2000, "admitted {admitted}");
+        assert!(l.snapshot().remaining <= 1000);
+    }
+
+    #[test]
+    fn should_never_over_admit_under_contention() {
+        let clock = MockClock::new();
+        let l = &Limiter::with_clock(1000, Duration::seconds(1), clock);
+
+        // Without any refill exactly the burst gets through, however the
+        // threads interleave
+        let admitted = std::thread::scope(|s| {
+            let workers: Vec<_> = (0..8)
+                .map(|i| {
+                    s.spawn(move || {
+                        (0..500)
+                            .filter(|_| !l.limit_n(i % 3 + 1))
+                            .map(|_| i % 3 + 1)
+                            .sum::<u64>()
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).sum::<u64>()
+        });
+
+        assert!(admitted <= 1000, "admitted {admitted}");
+        assert_eq!(l.snapshot().remaining, 1000 - admitted);
+    }
+
+    #[test]
+    fn should_admit_at_most_the_rate_while_time_passes() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(100, Duration::seconds(1), clock.clone());
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        let admitted = std::thread::scope(|s| {
+            let workers: Vec<_> = (0..8)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut admitted = 0;
+                        while !done.load(Ordering::Relaxed) {
+                            if !l.limit() {
+                                admitted += 1;
+                            }
+                        }
+                        admitted
+                    })
+                })
+                .collect();
+
+            for _ in 0..100 {
+                clock.advance(StdDuration::from_millis(10));
+                std::thread::yield_now();
+            }
+            done.store(true, Ordering::Relaxed);
+
+            workers.into_iter().map(|w| w.join().unwrap()).sum::<u64>()
+        });
+
+        // One second at 100/s on top of a full bucket
+        let left = l.snapshot().remaining;
+        assert!(admitted + left <= 200, "admitted {admitted}, left {left}");
+    }
+
+    #[test]
+    fn should_keep_allowance_consistent_under_mixed_operations() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(50, Duration::seconds(1), clock.clone());
+
+        std::thread::scope(|s| {
+            for i in 0..8u64 {
+                let l = &l;
+                s.spawn(move || {
+                    for j in 0..2000u64 {
+                        match (i + j) % 4 {
+                            0 => {
+                                if !l.limit_n(2) {
+                                    l.undo_n(2);
+                                }
+                            }
+                            1 => {
+                                if let Ok(r) = l.reserve(3) {
+                                    r.cancel();
+                                }
+                            }
+                            2 => {
+                                let d = l.check();
+                                if d.allowed {
+                                    l.undo();
+                                }
+                            }
+                            _ => {
+                                let s = l.snapshot();
+                                assert!(s.remaining <= s.capacity);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        // Every operation was refunded, so the bucket ends up full again
+        // rather than over- or underflowing
+        let s = l.snapshot();
+        assert_eq!(s.remaining, 50);
+        assert!(l.is_full());
+    }
+
+    #[test]
+    fn should_track_stats() {
+        let clock = MockClock::new();
+        let l = Limiter::builder()
+            .rate(10)
+            .track_stats()
+            .clock(clock.clone())
+            .build();
+
+        assert!(!l.limit_n(4));
+        assert!(l.check_n(1).is_allowed());
+        assert!(l.limit_n(8));
+        assert!(l.try_acquire(20).is_err());
+        l.undo_n(2);
+
+        // Reservations count the whole outstanding debt
+        let r = l.reserve(10).unwrap();
+        r.commit();
+        let r = l.reserve(5).unwrap();
+        r.cancel();
+
+        let stats = l.stats().unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                allowed: 4,
+                denied: 2,
+                admitted: 20,
+                undone: 2,
+                peak_deficit: 15,
+            }
+        );
+
+        l.reset_stats();
+        assert_eq!(l.stats(), Some(Stats::default()));
+        assert_eq!(Limiter::new(10, Duration::seconds(1)).stats(), None);
+    }
 }
@@ -0,0 +1,273 @@
+use std::fmt::Write;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use crate::{Clock, KeyedLimiter, RateLimiter, Snapshot, Stats};
+
+// Sample is the state of a single limiter or bucket at collection time
+struct Sample {
+    name: Arc<str>,
+    key: Option<String>,
+    snapshot: Snapshot,
+    stats: Option<Stats>,
+}
+
+type Collector = Box<dyn Fn(&mut Vec<Sample>) + Send + Sync>;
+
+// Family describes one exported metric: its name, type, help text and how
+// to read its value off a sample, if the sample has one
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&Sample) -> Option<String>,
+}
+
+const FAMILIES: [Family; 6] = [
+    Family {
+        name: "limit_remaining",
+        kind: "gauge",
+        help: "Units currently available.",
+        value: |s| Some(s.snapshot.remaining.to_string()),
+    },
+    Family {
+        name: "limit_capacity",
+        kind: "gauge",
+        help: "Units the limiter holds at most.",
+        value: |s| Some(s.snapshot.capacity.to_string()),
+    },
+    Family {
+        name: "limit_rate",
+        kind: "gauge",
+        help: "Units refilled per period.",
+        value: |s| Some(s.snapshot.rate.to_string()),
+    },
+    Family {
+        name: "limit_period_seconds",
+        kind: "gauge",
+        help: "Period the rate applies to.",
+        value: |s| Some(s.snapshot.period.as_secs_f64().to_string()),
+    },
+    Family {
+        name: "limit_allowed_total",
+        kind: "counter",
+        help: "Requests admitted.",
+        value: |s| s.stats.map(|s| s.allowed.to_string()),
+    },
+    Family {
+        name: "limit_denied_total",
+        kind: "counter",
+        help: "Requests rejected.",
+        value: |s| s.stats.map(|s| s.denied.to_string()),
+    },
+];
+
+// Registry collects the state of named limiters for export. Allowed and
+// denied totals are only reported for limiters that track statistics.
+// Registry instances are thread-safe.
+#[derive(Default)]
+pub struct Registry {
+    collectors: Mutex<Vec<Collector>>,
+}
+
+impl Registry {
+    // New creates an empty registry
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    // Register adds limiter under name
+    pub fn register(&self, name: &str, limiter: Arc<dyn RateLimiter>) {
+        let name: Arc<str> = name.into();
+
+        self.add(Box::new(move |out| {
+            out.push(Sample {
+                name: name.clone(),
+                key: None,
+                snapshot: limiter.snapshot(),
+                stats: limiter.stats(),
+            })
+        }));
+    }
+
+    // RegisterKeyed adds every bucket of limiter under name, labelled with
+    // its key as formatted by label
+    pub fn register_keyed<K, C>(
+        &self,
+        name: &str,
+        limiter: Arc<KeyedLimiter<K, C>>,
+        label: fn(&K) -> String,
+    ) where
+        K: Hash + Eq + Clone + Send + Sync + 'static,
+        C: Clock + Clone + Send + Sync + 'static,
+    {
+        let name: Arc<str> = name.into();
+
+        self.add(Box::new(move |out| {
+            limiter.for_each(|k, l| {
+                out.push(Sample {
+                    name: name.clone(),
+                    key: Some(label(k)),
+                    snapshot: l.snapshot(),
+                    stats: l.stats(),
+                })
+            })
+        }));
+    }
+
+    // Render returns the current state of every registered limiter in the
+    // Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let samples = self.collect();
+        let mut out = String::new();
+
+        for family in &FAMILIES {
+            let mut lines = samples
+                .iter()
+                .filter_map(|s| Some((s, (family.value)(s)?)))
+                .peekable();
+            if lines.peek().is_none() {
+                continue;
+            }
+
+            writeln!(out, "# HELP {} {}", family.name, family.help).unwrap();
+            writeln!(out, "# TYPE {} {}", family.name, family.kind).unwrap();
+            for (s, value) in lines {
+                write!(out, "{}{{name=\"{}\"", family.name, escape(&s.name)).unwrap();
+                if let Some(key) = &s.key {
+                    write!(out, ",key=\"{}\"", escape(key)).unwrap();
+                }
+                writeln!(out, "}} {value}").unwrap();
+            }
+        }
+
+        out
+    }
+
+    // Record publishes the current state of every registered limiter to the
+    // recorder installed with the metrics crate, under the same names and
+    // labels Render uses
+    #[cfg(feature = "metrics")]
+    pub fn record(&self) {
+        for s in self.collect() {
+            let mut labels = vec![::metrics::Label::new("name", s.name.to_string())];
+            if let Some(key) = s.key {
+                labels.push(::metrics::Label::new("key", key));
+            }
+
+            let snapshot = s.snapshot;
+            ::metrics::gauge!("limit_remaining", labels.clone()).set(snapshot.remaining as f64);
+            ::metrics::gauge!("limit_capacity", labels.clone()).set(snapshot.capacity as f64);
+            ::metrics::gauge!("limit_rate", labels.clone()).set(snapshot.rate as f64);
+            ::metrics::gauge!("limit_period_seconds", labels.clone())
+                .set(snapshot.period.as_secs_f64());
+
+            if let Some(stats) = s.stats {
+                ::metrics::counter!("limit_allowed_total", labels.clone()).absolute(stats.allowed);
+                ::metrics::counter!("limit_denied_total", labels).absolute(stats.denied);
+            }
+        }
+    }
+
+    fn add(&self, collector: Collector) {
+        self.collectors.lock().unwrap().push(collector);
+    }
+
+    fn collect(&self) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        for collect in self.collectors.lock().unwrap().iter() {
+            collect(&mut samples);
+        }
+
+        // Keep the output stable across renders despite hash map ordering
+        samples.sort_by(|a, b| (&a.name, &a.key).cmp(&(&b.name, &b.key)));
+        samples
+    }
+}
+
+// escape quotes a label value as the exposition format requires
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::{GcraLimiter, Limiter, MockClock};
+
+    #[test]
+    fn should_render_registered_limiters() {
+        let clock = MockClock::new();
+        let api = Arc::new(
+            Limiter::builder()
+                .rate(10)
+                .per(Duration::minutes(1))
+                .track_stats()
+                .clock(clock.clone())
+                .build(),
+        );
+        let gcra = Arc::new(GcraLimiter::with_clock(
+            5,
+            Duration::seconds(1),
+            clock.clone(),
+        ));
+        let keyed =
+            Arc::new(KeyedLimiter::with_clock(2, Duration::seconds(1), clock.clone()).with_stats());
+
+        let registry = Registry::new();
+        registry.register("api", api.clone());
+        registry.register("gcra", gcra.clone());
+        registry.register_keyed("login", keyed.clone(), |k: &&str| k.to_string());
+
+        assert!(!api.limit_n(4));
+        assert!(api.limit_n(7));
+        assert!(!gcra.limit());
+        assert!(!keyed.limit(&"b\"ob"));
+        assert!(!keyed.limit_n(&"alice", 2));
+        assert!(keyed.limit(&"alice"));
+
+        assert_eq!(
+            registry.render(),
+            "# HELP limit_remaining Units currently available.
+# TYPE limit_remaining gauge
+limit_remaining{name=\"api\"} 6
+limit_remaining{name=\"gcra\"} 4
+limit_remaining{name=\"login\",key=\"alice\"} 0
+limit_remaining{name=\"login\",key=\"b\\\"ob\"} 1
+# HELP limit_capacity Units the limiter holds at most.
+# TYPE limit_capacity gauge
+limit_capacity{name=\"api\"} 10
+limit_capacity{name=\"gcra\"} 5
+limit_capacity{name=\"login\",key=\"alice\"} 2
+limit_capacity{name=\"login\",key=\"b\\\"ob\"} 2
+# HELP limit_rate Units refilled per period.
+# TYPE limit_rate gauge
+limit_rate{name=\"api\"} 10
+limit_rate{name=\"gcra\"} 5
+limit_rate{name=\"login\",key=\"alice\"} 2
+limit_rate{name=\"login\",key=\"b\\\"ob\"} 2
+# HELP limit_period_seconds Period the rate applies to.
+# TYPE limit_period_seconds gauge
+limit_period_seconds{name=\"api\"} 60
+limit_period_seconds{name=\"gcra\"} 1
+limit_period_seconds{name=\"login\",key=\"alice\"} 1
+limit_period_seconds{name=\"login\",key=\"b\\\"ob\"} 1
+# HELP limit_allowed_total Requests admitted.
+# TYPE limit_allowed_total counter
+limit_allowed_total{name=\"api\"} 1
+limit_allowed_total{name=\"login\",key=\"alice\"} 1
+limit_allowed_total{name=\"login\",key=\"b\\\"ob\"} 1
+# HELP limit_denied_total Requests rejected.
+# TYPE limit_denied_total counter
+limit_denied_total{name=\"api\"} 1
+limit_denied_total{name=\"login\",key=\"alice\"} 1
+limit_denied_total{name=\"login\",key=\"b\\\"ob\"} 0
+"
+        );
+    }
+}
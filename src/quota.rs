@@ -0,0 +1,335 @@
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, TimeZone, Utc};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::{AcquireError, Clock, Decision, LimiterError, RateLimiter, Snapshot, SystemClock};
+
+// QuotaPeriod is the calendar interval after which a quota resets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    // Resets at midnight
+    Daily,
+    // Resets at midnight on Monday
+    Weekly,
+    // Resets at midnight on the first day of the month
+    Monthly,
+}
+
+// QuotaLimiter admits up to quota units per calendar period in the time zone
+// tz, resetting at the period boundary rather than refilling continuously.
+// The clock must count from the unix epoch. QuotaLimiter instances are
+// thread-safe.
+pub struct QuotaLimiter<Tz: TimeZone = Utc, C = SystemClock> {
+    quota: AtomicU64,
+    period: QuotaPeriod,
+    tz: Tz,
+    window: Mutex<Window>,
+    clock: C,
+}
+
+struct Window {
+    used: u64,
+    start: u64,
+    reset_at: u64,
+}
+
+impl<Tz: TimeZone> QuotaLimiter<Tz> {
+    // New creates a new quota limiter reading the system wall clock
+    pub fn new(quota: u64, period: QuotaPeriod, tz: Tz) -> QuotaLimiter<Tz> {
+        QuotaLimiter::with_clock(quota, period, tz, SystemClock)
+    }
+
+    // TryNew is like New but reports a quota of zero
+    pub fn try_new(
+        quota: u64,
+        period: QuotaPeriod,
+        tz: Tz,
+    ) -> Result<QuotaLimiter<Tz>, LimiterError> {
+        QuotaLimiter::try_with_clock(quota, period, tz, SystemClock)
+    }
+}
+
+impl<Tz: TimeZone, C: Clock> QuotaLimiter<Tz, C> {
+    // WithClock creates a new quota limiter reading time from clock. Like
+    // Limiter::new it adjusts a quota of zero to one.
+    pub fn with_clock(quota: u64, period: QuotaPeriod, tz: Tz, clock: C) -> QuotaLimiter<Tz, C> {
+        QuotaLimiter::try_with_clock(quota.max(1), period, tz, clock)
+            .expect("quota is at least one")
+    }
+
+    // TryWithClock is like WithClock but reports a quota of zero
+    pub fn try_with_clock(
+        quota: u64,
+        period: QuotaPeriod,
+        tz: Tz,
+        clock: C,
+    ) -> Result<QuotaLimiter<Tz, C>, LimiterError> {
+        if quota == 0 {
+            return Err(LimiterError::ZeroRate);
+        }
+
+        let mut l = QuotaLimiter {
+            quota: AtomicU64::new(quota),
+            period,
+            tz,
+            window: Mutex::new(Window {
+                used: 0,
+                start: 0,
+                reset_at: 0,
+            }),
+            clock,
+        };
+
+        let (start, reset_at) = l.bounds(l.clock.now());
+        let w = l.window.get_mut().unwrap();
+        w.start = start;
+        w.reset_at = reset_at;
+        Ok(l)
+    }
+
+    // Update rate updates the quota, taking effect in the current period.
+    // Units already used count against the new quota.
+    pub fn update_rate(&self, quota: i64) -> Result<(), LimiterError> {
+        if quota < 1 {
+            return Err(LimiterError::ZeroRate);
+        }
+        self.quota.store(quota as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // TryAcquire consumes cost units of the current period's quota
+    pub fn try_acquire(&self, cost: u64) -> Result<(), AcquireError> {
+        let quota = self.quota.load(Ordering::Relaxed);
+        if cost > quota {
+            return Err(AcquireError::InsufficientCapacity {
+                cost,
+                capacity: quota,
+            });
+        }
+
+        match self.check_n(cost).allowed {
+            true => Ok(()),
+            false => Err(AcquireError::RateLimited),
+        }
+    }
+
+    // CheckN consumes cost units of the current period's quota if available
+    // and reports the full decision
+    pub fn check_n(&self, cost: u64) -> Decision {
+        let quota = self.quota.load(Ordering::Relaxed);
+        let (mut w, now) = self.current();
+
+        let allowed = w.used.saturating_add(cost) <= quota;
+        if allowed {
+            w.used += cost;
+        }
+
+        let reset_after = Duration::from_nanos(w.reset_at.saturating_sub(now));
+        let retry_after = if cost > quota {
+            Duration::MAX
+        } else if w.used.saturating_add(cost) <= quota {
+            Duration::ZERO
+        } else {
+            reset_after
+        };
+
+        Decision {
+            allowed,
+            remaining: quota.saturating_sub(w.used),
+            retry_after,
+            reset_after: if w.used > 0 {
+                reset_after
+            } else {
+                Duration::ZERO
+            },
+        }
+    }
+
+    // Snapshot reports the current state without consuming anything
+    pub fn snapshot(&self) -> Snapshot {
+        let quota = self.quota.load(Ordering::Relaxed);
+        let (w, now) = self.current();
+
+        Snapshot {
+            capacity: quota,
+            remaining: quota.saturating_sub(w.used),
+            rate: quota,
+            period: Duration::from_nanos(w.reset_at - w.start),
+            reset_after: match w.used {
+                0 => Duration::ZERO,
+                _ => Duration::from_nanos(w.reset_at.saturating_sub(now)),
+            },
+        }
+    }
+
+    // UndoN returns n units to the current period's quota
+    pub fn undo_n(&self, n: u64) {
+        let (mut w, _) = self.current();
+        w.used = w.used.saturating_sub(n);
+    }
+
+    // Remaining returns the units left in the current period
+    pub fn remaining(&self) -> u64 {
+        let quota = self.quota.load(Ordering::Relaxed);
+        quota.saturating_sub(self.current().0.used)
+    }
+
+    // ResetAt returns when the current period ends and the quota is restored
+    pub fn reset_at(&self) -> DateTime<Tz> {
+        let reset_at = self.current().0.reset_at;
+        Utc.timestamp_nanos(reset_at as i64).with_timezone(&self.tz)
+    }
+
+    // current returns the window for the current period along with the time
+    // it was read at, starting a new one if the previous period has ended
+    fn current(&self) -> (MutexGuard<'_, Window>, u64) {
+        let now = self.clock.now();
+        let mut w = self.window.lock().unwrap();
+
+        if now >= w.reset_at {
+            let (start, reset_at) = self.bounds(now);
+            w.used = 0;
+            w.start = start;
+            w.reset_at = reset_at;
+        }
+
+        (w, now)
+    }
+
+    // bounds returns the start and end of the period containing now, in unix
+    // nanos
+    fn bounds(&self, now: u64) -> (u64, u64) {
+        let local = Utc
+            .timestamp_nanos(now as i64)
+            .with_timezone(&self.tz)
+            .date_naive();
+
+        let (start, end) = match self.period {
+            QuotaPeriod::Daily => (local, local + Days::new(1)),
+            QuotaPeriod::Weekly => {
+                let start = local - Days::new(local.weekday().num_days_from_monday() as u64);
+                (start, start + Days::new(7))
+            }
+            QuotaPeriod::Monthly => {
+                let start = NaiveDate::from_ymd_opt(local.year(), local.month(), 1).unwrap();
+                (start, start + Months::new(1))
+            }
+        };
+
+        (self.midnight(start), self.midnight(end))
+    }
+
+    // midnight returns the start of date in the limiter's time zone
+    fn midnight(&self, date: NaiveDate) -> u64 {
+        // Midnight can fall into a DST gap, in which case the day starts at
+        // the first valid local time after it
+        let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+        let t = (0..=24)
+            .find_map(|q| {
+                let t = midnight + chrono::Duration::minutes(q * 15);
+                self.tz.from_local_datetime(&t).earliest()
+            })
+            .expect("no valid local time within six hours of midnight");
+
+        t.timestamp_nanos_opt().unwrap_or(i64::MAX) as u64
+    }
+}
+
+impl<Tz, C> RateLimiter for QuotaLimiter<Tz, C>
+where
+    Tz: TimeZone + Send + Sync,
+    C: Clock + Send + Sync,
+{
+    fn check_n(&self, n: u64) -> Decision {
+        QuotaLimiter::check_n(self, n)
+    }
+
+    fn undo_n(&self, n: u64) {
+        QuotaLimiter::undo_n(self, n)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        QuotaLimiter::snapshot(self)
+    }
+
+    fn update_rate(&self, rate: i64) -> Result<(), LimiterError> {
+        QuotaLimiter::update_rate(self, rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::FixedOffset;
+
+    use super::*;
+    use crate::MockClock;
+
+    fn at(rfc3339: &str) -> Duration {
+        let t = DateTime::parse_from_rfc3339(rfc3339).unwrap();
+        Duration::from_nanos(t.timestamp_nanos_opt().unwrap() as u64)
+    }
+
+    #[test]
+    fn should_reset_at_utc_midnight() {
+        let clock = MockClock::new();
+        clock.set(at("2024-03-10T22:00:00Z"));
+        let l = QuotaLimiter::with_clock(3, QuotaPeriod::Daily, Utc, clock.clone());
+
+        assert!(!l.limit_n(2));
+        assert_eq!(l.remaining(), 1);
+        assert!(l.limit_n(2));
+        assert_eq!(l.reset_at().to_rfc3339(), "2024-03-11T00:00:00+00:00");
+
+        clock.set(at("2024-03-10T23:59:59Z"));
+        assert!(!l.limit());
+        assert!(l.limit());
+
+        clock.set(at("2024-03-11T00:00:00Z"));
+        assert_eq!(l.remaining(), 3);
+        assert!(!l.limit_n(3));
+        assert_eq!(l.reset_at().to_rfc3339(), "2024-03-12T00:00:00+00:00");
+
+        let d = l.check();
+        assert!(d.is_limited());
+        assert_eq!(d.retry_after, Duration::from_secs(86400));
+        assert_eq!(l.snapshot().period, Duration::from_secs(86400));
+    }
+
+    #[test]
+    fn should_align_to_local_calendar() {
+        let clock = MockClock::new();
+        clock.set(at("2024-01-31T20:00:00Z"));
+        let tz = FixedOffset::east_opt(5 * 3600).unwrap();
+
+        let monthly = QuotaLimiter::with_clock(10, QuotaPeriod::Monthly, tz, clock.clone());
+        assert_eq!(monthly.reset_at().to_rfc3339(), "2024-03-01T00:00:00+05:00");
+
+        let weekly = QuotaLimiter::with_clock(10, QuotaPeriod::Weekly, tz, clock.clone());
+        assert_eq!(weekly.reset_at().to_rfc3339(), "2024-02-05T00:00:00+05:00");
+    }
+
+    #[test]
+    fn should_refund_within_period() {
+        let clock = MockClock::new();
+        let l = QuotaLimiter::with_clock(5, QuotaPeriod::Daily, Utc, clock);
+
+        assert!(!l.limit_n(5));
+        l.undo_n(2);
+        assert_eq!(l.remaining(), 2);
+        assert!(l.try_acquire(6).is_err());
+    }
+
+    #[test]
+    fn should_report_zero_quotas() {
+        assert_eq!(
+            QuotaLimiter::try_new(0, QuotaPeriod::Daily, Utc).err(),
+            Some(LimiterError::ZeroRate)
+        );
+
+        let l = QuotaLimiter::with_clock(0, QuotaPeriod::Daily, Utc, MockClock::new());
+        assert_eq!(l.snapshot().capacity, 1);
+        assert!(!l.limit());
+        assert!(l.limit());
+    }
+}
@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use crate::{Clock, Limiter, MonotonicClock};
+
+// Reservation holds units taken from a Limiter by reserve(). Unless committed
+// the units are refunded when it is cancelled or dropped.
+#[must_use = "dropping a reservation immediately refunds it"]
+pub struct Reservation<'a, C: Clock = MonotonicClock> {
+    limiter: &'a Limiter<C>,
+    cost: u64,
+    ready_at: u64,
+    done: bool,
+}
+
+impl<'a, C: Clock> Reservation<'a, C> {
+    pub(crate) fn new(limiter: &'a Limiter<C>, cost: u64, ready_at: u64) -> Self {
+        Reservation {
+            limiter,
+            cost,
+            ready_at,
+            done: false,
+        }
+    }
+
+    // Cost returns the number of units reserved
+    pub fn cost(&self) -> u64 {
+        self.cost
+    }
+
+    // Delay returns how long to wait before acting on the reservation
+    pub fn delay(&self) -> Duration {
+        let now = self.limiter.clock().now();
+        Duration::from_nanos(self.ready_at.saturating_sub(now))
+    }
+
+    // Commit keeps the reserved units consumed
+    pub fn commit(mut self) {
+        self.done = true;
+    }
+
+    // Cancel refunds the reserved units. Whatever the bucket refilled in the
+    // meantime counts against the refund, so the limiter ends up as if the
+    // reservation had never been made.
+    pub fn cancel(mut self) {
+        self.refund();
+    }
+
+    fn refund(&mut self) {
+        if !self.done {
+            self.done = true;
+            self.limiter.undo_n(self.cost);
+        }
+    }
+}
+
+impl<C: Clock> Drop for Reservation<'_, C> {
+    fn drop(&mut self) {
+        self.refund();
+    }
+}
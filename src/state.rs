@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use portable_atomic::{AtomicU128, Ordering};
+
+// State is the part of a Limiter that changes on every call: its allowance
+// and the time it was last refilled at. Last check runs ahead of the clock
+// while reservations are being paid off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct State {
+    pub allowance: u64,
+    pub last_check: u64,
+}
+
+impl State {
+    // refill credits the time passed between the last check and now at rate,
+    // never exceeding max. The last check never moves backwards, so callers
+    // with slightly older readings cannot get the same interval credited
+    // twice, and long idle times saturate rather than overflow.
+    pub fn refill(self, now: u64, rate: u64, max: u64) -> State {
+        let passed = now.saturating_sub(self.last_check);
+
+        State {
+            allowance: self
+                .allowance
+                .saturating_add(passed.saturating_mul(rate))
+                .min(max),
+            last_check: self.last_check.max(now),
+        }
+    }
+
+    // debt returns how long the last check runs ahead of now, during which
+    // nothing refills
+    pub fn debt(self, now: u64) -> Duration {
+        Duration::from_nanos(self.last_check.saturating_sub(now))
+    }
+
+    fn pack(self) -> u128 {
+        (self.last_check as u128) << 64 | self.allowance as u128
+    }
+
+    fn unpack(v: u128) -> State {
+        State {
+            allowance: v as u64,
+            last_check: (v >> 64) as u64,
+        }
+    }
+}
+
+// AtomicState packs a State into a single atomic word, so a refill and the
+// deduction that follows it happen in one compare-and-swap. Every operation
+// on it is linearizable: no caller can act on an allowance another caller
+// has already spent.
+pub(crate) struct AtomicState(AtomicU128);
+
+impl AtomicState {
+    pub fn new(state: State) -> AtomicState {
+        AtomicState(AtomicU128::new(state.pack()))
+    }
+
+    pub fn load(&self) -> State {
+        State::unpack(self.0.load(Ordering::Relaxed))
+    }
+
+    // update applies f to the current state until its result is stored
+    // without interference and returns the value f produced alongside it. A
+    // state f leaves unchanged is not written back.
+    pub fn update<T>(&self, mut f: impl FnMut(State) -> (State, T)) -> T {
+        let mut prev = self.0.load(Ordering::Relaxed);
+
+        loop {
+            let (next, out) = f(State::unpack(prev));
+            let next = next.pack();
+            if next == prev {
+                return out;
+            }
+
+            match self
+                .0
+                .compare_exchange_weak(prev, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return out,
+                Err(x) => prev = x,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_round_trip_packed_state() {
+        let s = State {
+            allowance: u64::MAX - 1,
+            last_check: 1 << 63,
+        };
+        assert_eq!(State::unpack(s.pack()), s);
+
+        let a = AtomicState::new(s);
+        let out = a.update(|s| (State { allowance: 7, ..s }, s.allowance));
+        assert_eq!(out, u64::MAX - 1);
+        assert_eq!(a.load().allowance, 7);
+        assert_eq!(a.load().last_check, 1 << 63);
+    }
+
+    #[test]
+    fn should_refill_without_moving_backwards() {
+        let s = State {
+            allowance: 10,
+            last_check: 100,
+        };
+
+        assert_eq!(
+            s.refill(105, 2, 1000),
+            State {
+                allowance: 20,
+                last_check: 105
+            }
+        );
+        assert_eq!(s.refill(50, 2, 1000), s);
+        assert_eq!(s.refill(u64::MAX, u64::MAX, 1000).allowance, 1000);
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Stats counts the decisions a Limiter has made since it was created or its
+// statistics were last reset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    // Requests admitted, including reservations
+    pub allowed: u64,
+    // Requests rejected
+    pub denied: u64,
+    // Units admitted across all allowed requests
+    pub admitted: u64,
+    // Undo calls, including refunded reservations
+    pub undone: u64,
+    // Most units a rejected request fell short by or a reservation borrowed
+    pub peak_deficit: u64,
+}
+
+// Counters backs Stats with one relaxed atomic per field, so recording a
+// decision never contends on a lock
+#[derive(Default)]
+pub(crate) struct Counters {
+    allowed: AtomicU64,
+    denied: AtomicU64,
+    admitted: AtomicU64,
+    undone: AtomicU64,
+    peak_deficit: AtomicU64,
+}
+
+impl Counters {
+    // record counts a decision on cost units which fell short by deficit
+    pub fn record(&self, allowed: bool, cost: u64, deficit: u64) {
+        if allowed {
+            self.allowed.fetch_add(1, Ordering::Relaxed);
+            self.admitted.fetch_add(cost, Ordering::Relaxed);
+        } else {
+            self.denied.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if deficit > 0 {
+            self.peak_deficit.fetch_max(deficit, Ordering::Relaxed);
+        }
+    }
+
+    pub fn undo(&self) {
+        self.undone.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            allowed: self.allowed.load(Ordering::Relaxed),
+            denied: self.denied.load(Ordering::Relaxed),
+            admitted: self.admitted.load(Ordering::Relaxed),
+            undone: self.undone.load(Ordering::Relaxed),
+            peak_deficit: self.peak_deficit.load(Ordering::Relaxed),
+        }
+    }
+
+    // reset zeroes every counter. Decisions recorded concurrently may be
+    // partially kept.
+    pub fn reset(&self) {
+        self.allowed.store(0, Ordering::Relaxed);
+        self.denied.store(0, Ordering::Relaxed);
+        self.admitted.store(0, Ordering::Relaxed);
+        self.undone.store(0, Ordering::Relaxed);
+        self.peak_deficit.store(0, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,217 @@
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream;
+use pin_project_lite::pin_project;
+
+#[cfg(any(feature = "tokio", feature = "async-std", feature = "futures-timer"))]
+use crate::DefaultTimer;
+use crate::{RateLimiter, Timer};
+
+// StreamExt adds rate limiting to streams
+pub trait StreamExt: Stream + Sized {
+    // RateLimit yields items only as limiter admits them, one unit per item,
+    // sleeping on the runtime selected by the enabled cargo features
+    #[cfg(any(feature = "tokio", feature = "async-std", feature = "futures-timer"))]
+    fn rate_limit<L>(self, limiter: L) -> RateLimitStream<Self, L, DefaultTimer>
+    where
+        L: Deref,
+        L::Target: RateLimiter,
+    {
+        self.rate_limit_with(limiter, DefaultTimer::default())
+    }
+
+    // RateLimitWith is like RateLimit but sleeps on timer
+    fn rate_limit_with<L, T>(self, limiter: L, timer: T) -> RateLimitStream<Self, L, T>
+    where
+        L: Deref,
+        L::Target: RateLimiter,
+        T: Timer,
+    {
+        RateLimitStream {
+            stream: self,
+            limiter,
+            timer,
+            cost: |_| 1,
+            pending: None,
+            delay: None,
+        }
+    }
+}
+
+impl<S: Stream> StreamExt for S {}
+
+pin_project! {
+    // RateLimitStream yields the items of a stream as its limiter admits
+    // them, holding back each item until its cost is available. Items
+    // costing more than the limiter can ever hold are yielded once it is
+    // full, using up all of it.
+    pub struct RateLimitStream<S: Stream, L, T: Timer, F = fn(&<S as Stream>::Item) -> u64> {
+        #[pin]
+        stream: S,
+        limiter: L,
+        timer: T,
+        cost: F,
+        // The item held back and its cost
+        pending: Option<(S::Item, u64)>,
+        delay: Option<Pin<Box<T::Delay>>>,
+    }
+}
+
+impl<S: Stream, L, T: Timer, F> RateLimitStream<S, L, T, F> {
+    // WithCost charges each item the number of units cost returns for it
+    // instead of one
+    pub fn with_cost<G>(self, cost: G) -> RateLimitStream<S, L, T, G>
+    where
+        G: FnMut(&S::Item) -> u64,
+    {
+        RateLimitStream {
+            stream: self.stream,
+            limiter: self.limiter,
+            timer: self.timer,
+            cost,
+            pending: self.pending,
+            delay: self.delay,
+        }
+    }
+}
+
+impl<S, L, T, F> Stream for RateLimitStream<S, L, T, F>
+where
+    S: Stream,
+    L: Deref,
+    L::Target: RateLimiter,
+    T: Timer,
+    F: FnMut(&S::Item) -> u64,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let mut this = self.project();
+
+        loop {
+            if let Some(delay) = this.delay {
+                if delay.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                *this.delay = None;
+            }
+
+            let (item, cost) = match this.pending.take() {
+                Some(pending) => pending,
+                None => match this.stream.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(item)) => {
+                        let cost = (this.cost)(&item);
+                        (item, cost)
+                    }
+                    Poll::Ready(None) => return Poll::Ready(None),
+                    Poll::Pending => return Poll::Pending,
+                },
+            };
+
+            let d = this.limiter.check_n(cost);
+            if d.allowed {
+                return Poll::Ready(Some(item));
+            }
+
+            if d.retry_after == Duration::MAX {
+                // Charge what the limiter can hold instead, once it is full.
+                // Limiters that cannot admit even that let the item through.
+                let capacity = this.limiter.snapshot().capacity;
+                if capacity >= cost {
+                    return Poll::Ready(Some(item));
+                }
+                *this.pending = Some((item, capacity));
+                continue;
+            }
+
+            *this.pending = Some((item, cost));
+            *this.delay = Some(Box::pin(this.timer.delay(d.retry_after)));
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let held = usize::from(self.pending.is_some());
+        let (lower, upper) = self.stream.size_hint();
+        (
+            lower.saturating_add(held),
+            upper.and_then(|u| u.checked_add(held)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::sync::Arc;
+
+    use chrono::Duration as ChronoDuration;
+
+    use super::*;
+    use crate::timer::testing::{poll, MockTimer};
+    use crate::{Clock, Limiter, MockClock};
+
+    struct Iter<I>(I);
+
+    impl<I: Iterator + Unpin> Stream for Iter<I> {
+        type Item = I::Item;
+
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<I::Item>> {
+            Poll::Ready(self.0.next())
+        }
+    }
+
+    // collect drains stream, recording when each item was yielded
+    fn collect<S: Stream>(stream: S, clock: &MockClock) -> Vec<(S::Item, u64)> {
+        let mut stream = std::pin::pin!(stream);
+        let mut items = Vec::new();
+
+        loop {
+            match poll(poll_fn(|cx| stream.as_mut().poll_next(cx))) {
+                Poll::Ready(Some(item)) => items.push((item, clock.now() / 1_000_000)),
+                Poll::Ready(None) => return items,
+                Poll::Pending => panic!("stream not ready"),
+            }
+        }
+    }
+
+    #[test]
+    fn should_yield_items_at_the_limiter_rate() {
+        let clock = MockClock::new();
+        let l = Limiter::with_clock(2, ChronoDuration::seconds(1), clock.clone());
+
+        let stream = Iter(1..=5).rate_limit_with(&l, MockTimer(clock.clone()));
+        assert_eq!(
+            collect(stream, &clock),
+            [(1, 0), (2, 0), (3, 500), (4, 1000), (5, 1500)]
+        );
+    }
+
+    #[test]
+    fn should_charge_items_their_cost() {
+        let clock = MockClock::new();
+        let l: Arc<dyn RateLimiter> = Arc::new(Limiter::with_clock(
+            10,
+            ChronoDuration::seconds(1),
+            clock.clone(),
+        ));
+
+        let stream = Iter(["a", "bbbbbbbb", "cccc", "dddddddddddddddd"].into_iter())
+            .rate_limit_with(l, MockTimer(clock.clone()))
+            .with_cost(|s: &&str| s.len() as u64);
+
+        // The last item can never fit, so it waits for a full bucket instead
+        assert_eq!(
+            collect(stream, &clock),
+            [
+                ("a", 0),
+                ("bbbbbbbb", 0),
+                ("cccc", 300),
+                ("dddddddddddddddd", 1300)
+            ]
+        );
+    }
+}
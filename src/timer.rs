@@ -0,0 +1,106 @@
+use std::future::Future;
+use std::time::Duration;
+
+// Timer lets async limiter methods sleep on whichever runtime the caller uses
+pub trait Timer {
+    type Delay: Future<Output = ()>;
+
+    // Delay returns a future that completes once d has elapsed
+    fn delay(&self, d: Duration) -> Self::Delay;
+}
+
+// TokioTimer sleeps with tokio::time::sleep
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioTimer;
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioTimer {
+    type Delay = tokio::time::Sleep;
+
+    fn delay(&self, d: Duration) -> Self::Delay {
+        tokio::time::sleep(d)
+    }
+}
+
+// AsyncStdTimer sleeps with async_std::task::sleep
+#[cfg(feature = "async-std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStdTimer;
+
+#[cfg(feature = "async-std")]
+impl Timer for AsyncStdTimer {
+    type Delay = std::pin::Pin<Box<dyn Future<Output = ()> + Send>>;
+
+    fn delay(&self, d: Duration) -> Self::Delay {
+        Box::pin(async_std::task::sleep(d))
+    }
+}
+
+// FuturesTimer sleeps with futures_timer::Delay and needs no runtime at all
+#[cfg(feature = "futures-timer")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FuturesTimer;
+
+#[cfg(feature = "futures-timer")]
+impl Timer for FuturesTimer {
+    type Delay = futures_timer::Delay;
+
+    fn delay(&self, d: Duration) -> Self::Delay {
+        futures_timer::Delay::new(d)
+    }
+}
+
+// DefaultTimer is the timer used by Limiter::acquire, picked from the enabled
+// features in order of preference: tokio, async-std, futures-timer.
+#[cfg(feature = "tokio")]
+pub type DefaultTimer = TokioTimer;
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+pub type DefaultTimer = AsyncStdTimer;
+
+#[cfg(all(
+    feature = "futures-timer",
+    not(any(feature = "tokio", feature = "async-std"))
+))]
+pub type DefaultTimer = FuturesTimer;
+
+// Testing holds the timer and executor helpers shared by the tests of the
+// async APIs
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::future::{Future, Ready};
+    use std::task::{Context, Poll, Waker};
+    use std::time::Duration;
+
+    use super::Timer;
+    use crate::MockClock;
+
+    // MockTimer completes every delay at once, advancing its clock by it
+    #[derive(Clone)]
+    pub struct MockTimer(pub MockClock);
+
+    impl Timer for MockTimer {
+        type Delay = Ready<()>;
+
+        fn delay(&self, d: Duration) -> Self::Delay {
+            self.0.advance(d);
+            std::future::ready(())
+        }
+    }
+
+    // poll polls f once
+    pub fn poll<F: Future>(f: F) -> Poll<F::Output> {
+        let mut cx = Context::from_waker(Waker::noop());
+        std::pin::pin!(f).poll(&mut cx)
+    }
+
+    // block_on returns the output of f, which must not have to wait for
+    // anything but MockTimer delays
+    pub fn block_on<F: Future>(f: F) -> F::Output {
+        match poll(f) {
+            Poll::Ready(v) => v,
+            Poll::Pending => panic!("future not ready"),
+        }
+    }
+}
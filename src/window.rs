@@ -0,0 +1,614 @@
+use chrono::Duration;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+
+use crate::builder;
+use crate::{AcquireError, Clock, Decision, LimiterError, MonotonicClock, RateLimiter, Snapshot};
+
+// SlidingWindowLog admits at most limit units in any rolling window by
+// remembering when every admitted request was made and what it cost. The log
+// never holds more than limit entries, however large the costs.
+// SlidingWindowLog instances are thread-safe.
+pub struct SlidingWindowLog<C = MonotonicClock> {
+    limit: AtomicU64,
+    window: u64,
+    log: Mutex<Log>,
+    clock: C,
+}
+
+// Log holds the admitted requests as runs of units admitted at the same time,
+// oldest first, and the total units they add up to
+#[derive(Default)]
+struct Log {
+    runs: VecDeque<Run>,
+    used: u64,
+}
+
+struct Run {
+    at: u64,
+    cost: u64,
+}
+
+impl SlidingWindowLog {
+    // New creates a new sliding window log admitting limit units per window
+    pub fn new(limit: i64, window: Duration) -> SlidingWindowLog {
+        SlidingWindowLog::with_clock(limit, window, MonotonicClock::new())
+    }
+
+    // TryNew is like New but reports limits below one, empty or negative
+    // windows and windows too long to represent in nanoseconds
+    pub fn try_new(limit: i64, window: Duration) -> Result<SlidingWindowLog, LimiterError> {
+        SlidingWindowLog::try_with_clock(limit, window, MonotonicClock::new())
+    }
+}
+
+impl<C: Clock> SlidingWindowLog<C> {
+    // WithClock creates a new sliding window log reading time from clock. Like
+    // Limiter::new it adjusts limits below one and empty or negative windows,
+    // and panics on windows too long to represent.
+    pub fn with_clock(limit: i64, window: Duration, clock: C) -> SlidingWindowLog<C> {
+        let window = builder::or_default_period(window);
+        SlidingWindowLog::try_with_clock(limit.max(1), window, clock)
+            .expect("limiter configuration overflows 64 bits")
+    }
+
+    // TryWithClock is like WithClock but reports invalid configurations
+    pub fn try_with_clock(
+        limit: i64,
+        window: Duration,
+        clock: C,
+    ) -> Result<SlidingWindowLog<C>, LimiterError> {
+        if limit < 1 {
+            return Err(LimiterError::ZeroRate);
+        }
+
+        Ok(SlidingWindowLog {
+            limit: AtomicU64::new(limit as u64),
+            window: builder::nanos(window)?,
+            log: Mutex::new(Log::default()),
+            clock,
+        })
+    }
+
+    // Update rate updates the number of units admitted per window
+    pub fn update_rate(&self, limit: i64) -> Result<(), LimiterError> {
+        if limit < 1 {
+            return Err(LimiterError::ZeroRate);
+        }
+        self.limit.store(limit as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // TryAcquire admits cost units if fewer than limit minus cost were
+    // admitted during the last window
+    pub fn try_acquire(&self, cost: u64) -> Result<(), AcquireError> {
+        let d = self.check_n(cost);
+        if d.allowed {
+            Ok(())
+        } else if d.retry_after == StdDuration::MAX {
+            Err(AcquireError::InsufficientCapacity {
+                cost,
+                capacity: self.limit.load(Ordering::Relaxed),
+            })
+        } else {
+            Err(AcquireError::RateLimited)
+        }
+    }
+
+    // CheckN admits cost units if there is room for them in the window and
+    // reports the full decision
+    pub fn check_n(&self, cost: u64) -> Decision {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let now = self.clock.now();
+        let mut log = self.log.lock().unwrap();
+        self.evict(&mut log, now);
+
+        let allowed = log.used.saturating_add(cost) <= limit;
+        if allowed && cost > 0 {
+            log.used += cost;
+            match log.runs.back_mut() {
+                Some(run) if run.at == now => run.cost += cost,
+                _ => log.runs.push_back(Run { at: now, cost }),
+            }
+        }
+
+        // Another request of the same cost fits once enough of the oldest
+        // units have slid out of the window
+        let retry_after = if cost > limit {
+            StdDuration::MAX
+        } else if log.used.saturating_add(cost) <= limit {
+            StdDuration::ZERO
+        } else {
+            let mut excess = log.used + cost - limit;
+            let run = log.runs.iter().find(|run| {
+                excess = excess.saturating_sub(run.cost);
+                excess == 0
+            });
+            self.expiry(run.unwrap().at, now)
+        };
+
+        Decision {
+            allowed,
+            remaining: limit.saturating_sub(log.used),
+            retry_after,
+            reset_after: log
+                .runs
+                .back()
+                .map_or(StdDuration::ZERO, |run| self.expiry(run.at, now)),
+        }
+    }
+
+    // Snapshot reports the current state without consuming anything
+    pub fn snapshot(&self) -> Snapshot {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let now = self.clock.now();
+        let mut log = self.log.lock().unwrap();
+        self.evict(&mut log, now);
+
+        Snapshot {
+            capacity: limit,
+            remaining: limit.saturating_sub(log.used),
+            rate: limit,
+            period: StdDuration::from_nanos(self.window),
+            reset_after: log
+                .runs
+                .back()
+                .map_or(StdDuration::ZERO, |run| self.expiry(run.at, now)),
+        }
+    }
+
+    // UndoN forgets the n most recently admitted units
+    pub fn undo_n(&self, mut n: u64) {
+        let mut log = self.log.lock().unwrap();
+
+        while let Some(run) = log.runs.back_mut() {
+            if run.cost > n {
+                run.cost -= n;
+                log.used -= n;
+                return;
+            }
+
+            n -= run.cost;
+            log.used -= run.cost;
+            log.runs.pop_back();
+        }
+    }
+
+    // evict forgets everything that has slid out of the window
+    fn evict(&self, log: &mut Log, now: u64) {
+        while let Some(run) = log.runs.front() {
+            if now.saturating_sub(run.at) < self.window {
+                break;
+            }
+            log.used -= run.cost;
+            log.runs.pop_front();
+        }
+    }
+
+    // expiry returns how long until units logged at t leave the window
+    fn expiry(&self, t: u64, now: u64) -> StdDuration {
+        StdDuration::from_nanos(t.saturating_add(self.window).saturating_sub(now))
+    }
+}
+
+impl<C: Clock + Send + Sync> RateLimiter for SlidingWindowLog<C> {
+    fn check_n(&self, n: u64) -> Decision {
+        SlidingWindowLog::check_n(self, n)
+    }
+
+    fn undo_n(&self, n: u64) {
+        SlidingWindowLog::undo_n(self, n)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        SlidingWindowLog::snapshot(self)
+    }
+
+    fn update_rate(&self, rate: i64) -> Result<(), LimiterError> {
+        SlidingWindowLog::update_rate(self, rate)
+    }
+}
+
+// SlidingWindowCounter approximates a sliding window with two fixed windows,
+// weighting the previous window's count by how much of it still overlaps the
+// sliding one. It needs constant memory regardless of limit.
+// SlidingWindowCounter instances are thread-safe.
+pub struct SlidingWindowCounter<C = MonotonicClock> {
+    limit: AtomicU64,
+    window: u64,
+    counter: Mutex<Counter>,
+    clock: C,
+}
+
+struct Counter {
+    start: u64,
+    curr: u64,
+    prev: u64,
+}
+
+impl SlidingWindowCounter {
+    // New creates a new sliding window counter admitting limit units per window
+    pub fn new(limit: i64, window: Duration) -> SlidingWindowCounter {
+        SlidingWindowCounter::with_clock(limit, window, MonotonicClock::new())
+    }
+
+    // TryNew is like New but reports invalid configurations, see
+    // SlidingWindowLog::try_new
+    pub fn try_new(limit: i64, window: Duration) -> Result<SlidingWindowCounter, LimiterError> {
+        SlidingWindowCounter::try_with_clock(limit, window, MonotonicClock::new())
+    }
+}
+
+impl<C: Clock> SlidingWindowCounter<C> {
+    // WithClock creates a new sliding window counter reading time from clock,
+    // adjusting its configuration like SlidingWindowLog::with_clock
+    pub fn with_clock(limit: i64, window: Duration, clock: C) -> SlidingWindowCounter<C> {
+        let window = builder::or_default_period(window);
+        SlidingWindowCounter::try_with_clock(limit.max(1), window, clock)
+            .expect("limiter configuration overflows 64 bits")
+    }
+
+    // TryWithClock is like WithClock but reports invalid configurations
+    pub fn try_with_clock(
+        limit: i64,
+        window: Duration,
+        clock: C,
+    ) -> Result<SlidingWindowCounter<C>, LimiterError> {
+        if limit < 1 {
+            return Err(LimiterError::ZeroRate);
+        }
+
+        Ok(SlidingWindowCounter {
+            limit: AtomicU64::new(limit as u64),
+            window: builder::nanos(window)?,
+            counter: Mutex::new(Counter {
+                start: clock.now(),
+                curr: 0,
+                prev: 0,
+            }),
+            clock,
+        })
+    }
+
+    // Update rate updates the number of units admitted per window
+    pub fn update_rate(&self, limit: i64) -> Result<(), LimiterError> {
+        if limit < 1 {
+            return Err(LimiterError::ZeroRate);
+        }
+        self.limit.store(limit as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // TryAcquire admits cost units if the weighted count of the current and
+    // previous windows leaves room for them
+    pub fn try_acquire(&self, cost: u64) -> Result<(), AcquireError> {
+        let d = self.check_n(cost);
+        if d.allowed {
+            Ok(())
+        } else if d.retry_after == StdDuration::MAX {
+            Err(AcquireError::InsufficientCapacity {
+                cost,
+                capacity: self.limit.load(Ordering::Relaxed),
+            })
+        } else {
+            Err(AcquireError::RateLimited)
+        }
+    }
+
+    // CheckN admits cost units if the weighted count leaves room for them and
+    // reports the full decision
+    pub fn check_n(&self, cost: u64) -> Decision {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let mut c = self.counter.lock().unwrap();
+        let elapsed = self.roll(&mut c);
+        let allowed = self.estimate(&c, elapsed).saturating_add(cost) <= limit;
+        if allowed {
+            c.curr += cost;
+        }
+
+        let retry_after = if cost > limit {
+            StdDuration::MAX
+        } else {
+            self.wait_for(&c, elapsed, limit - cost)
+        };
+
+        Decision {
+            allowed,
+            remaining: limit.saturating_sub(self.estimate(&c, elapsed)),
+            retry_after,
+            reset_after: self.wait_for(&c, elapsed, 0),
+        }
+    }
+
+    // Snapshot reports the current state without consuming anything
+    pub fn snapshot(&self) -> Snapshot {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let mut c = self.counter.lock().unwrap();
+        let elapsed = self.roll(&mut c);
+
+        Snapshot {
+            capacity: limit,
+            remaining: limit.saturating_sub(self.estimate(&c, elapsed)),
+            rate: limit,
+            period: StdDuration::from_nanos(self.window),
+            reset_after: self.wait_for(&c, elapsed, 0),
+        }
+    }
+
+    // UndoN returns n units to the current window
+    pub fn undo_n(&self, n: u64) {
+        let mut c = self.counter.lock().unwrap();
+        c.curr = c.curr.saturating_sub(n);
+    }
+
+    // roll moves the fixed windows forward to the one containing the current
+    // time and returns how far into it we are. The clock is read under the
+    // lock, and a clock that went backwards counts as the window's start.
+    fn roll(&self, c: &mut Counter) -> u64 {
+        let elapsed = self.clock.now().saturating_sub(c.start);
+        let passed = elapsed / self.window;
+        if passed > 0 {
+            c.prev = if passed == 1 { c.curr } else { 0 };
+            c.curr = 0;
+            c.start += passed * self.window;
+        }
+        elapsed % self.window
+    }
+
+    // estimate returns the weighted count elapsed nanoseconds into the current
+    // window. The previous window's share is rounded up so we never
+    // over-admit.
+    fn estimate(&self, c: &Counter, elapsed: u64) -> u64 {
+        let weighted =
+            (c.prev as u128 * (self.window - elapsed) as u128).div_ceil(self.window as u128);
+        (weighted as u64).saturating_add(c.curr)
+    }
+
+    // wait_for returns how long until the estimate drops to room at most
+    fn wait_for(&self, c: &Counter, elapsed: u64, room: u64) -> StdDuration {
+        let w = self.window as u128;
+
+        // The weight of a count decays linearly over the following window,
+        // so it is down to room at most this far into that window
+        let decay = |count: u64, room: u64| {
+            if count <= room {
+                0
+            } else {
+                (w - room as u128 * w / count as u128) as u64
+            }
+        };
+
+        let wait = if self.estimate(c, elapsed) <= room {
+            0
+        } else if c.curr <= room {
+            // Only the previous window's share has to decay
+            decay(c.prev, room - c.curr).saturating_sub(elapsed)
+        } else {
+            // The current window has to become the previous one first
+            self.window - elapsed + decay(c.curr, room)
+        };
+
+        StdDuration::from_nanos(wait)
+    }
+}
+
+impl<C: Clock + Send + Sync> RateLimiter for SlidingWindowCounter<C> {
+    fn check_n(&self, n: u64) -> Decision {
+        SlidingWindowCounter::check_n(self, n)
+    }
+
+    fn undo_n(&self, n: u64) {
+        SlidingWindowCounter::undo_n(self, n)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        SlidingWindowCounter::snapshot(self)
+    }
+
+    fn update_rate(&self, rate: i64) -> Result<(), LimiterError> {
+        SlidingWindowCounter::update_rate(self, rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+
+    #[test]
+    fn should_limit_rolling_window_exactly() {
+        let clock = MockClock::new();
+        let l = SlidingWindowLog::with_clock(3, Duration::seconds(60), clock.clone());
+
+        assert!(!l.limit());
+        clock.advance(StdDuration::from_secs(30));
+        assert!(!l.limit_n(2));
+        assert!(l.limit());
+
+        // The first call slides out, the other two are still in the window
+        clock.advance(StdDuration::from_secs(30));
+        assert!(!l.limit());
+        assert!(l.limit());
+
+        l.undo();
+        assert!(!l.limit());
+
+        clock.advance(StdDuration::from_secs(30));
+        assert!(!l.limit_n(2));
+        assert!(l.try_acquire(4).is_err());
+    }
+
+    #[test]
+    fn should_log_weighted_costs_as_runs() {
+        let clock = MockClock::new();
+        let l = SlidingWindowLog::with_clock(i64::MAX, Duration::seconds(60), clock.clone());
+
+        // A huge cost is a single entry rather than one per unit
+        assert!(!l.limit_n(1 << 61));
+        assert_eq!(l.log.lock().unwrap().runs.len(), 1);
+
+        clock.advance(StdDuration::from_secs(20));
+        assert!(!l.limit_n(1 << 61));
+        let d = l.check_n(1 << 62);
+        assert!(d.is_limited());
+        assert_eq!(d.retry_after, StdDuration::from_secs(40));
+        assert_eq!(d.reset_after, StdDuration::from_secs(60));
+
+        // Undoing spans runs, oldest last
+        l.undo_n((1 << 61) + 5);
+        assert_eq!(l.snapshot().remaining, i64::MAX as u64 - (1 << 61) + 5);
+        assert_eq!(l.log.lock().unwrap().runs.len(), 1);
+
+        clock.advance(StdDuration::from_secs(40));
+        assert_eq!(l.snapshot().remaining, i64::MAX as u64);
+    }
+
+    #[test]
+    fn should_weight_previous_window() {
+        let clock = MockClock::new();
+        let l = SlidingWindowCounter::with_clock(10, Duration::seconds(60), clock.clone());
+
+        assert!(!l.limit_n(10));
+        assert!(l.limit());
+
+        // A quarter into the next window, 3/4 of the previous count remains
+        clock.advance(StdDuration::from_secs(75));
+        assert!(!l.limit_n(2));
+        assert!(l.limit());
+
+        l.undo_n(2);
+        assert!(!l.limit_n(2));
+
+        // Two windows later nothing is remembered
+        clock.advance(StdDuration::from_secs(120));
+        assert!(!l.limit_n(10));
+    }
+
+    #[test]
+    fn should_share_rate_limiter_trait() {
+        let clock = MockClock::new();
+        let limiters: Vec<Box<dyn RateLimiter>> = vec![
+            Box::new(crate::Limiter::with_clock(
+                5,
+                Duration::seconds(1),
+                clock.clone(),
+            )),
+            Box::new(crate::GcraLimiter::with_clock(
+                5,
+                Duration::seconds(1),
+                clock.clone(),
+            )),
+            Box::new(SlidingWindowLog::with_clock(
+                5,
+                Duration::seconds(1),
+                clock.clone(),
+            )),
+            Box::new(SlidingWindowCounter::with_clock(
+                5,
+                Duration::seconds(1),
+                clock.clone(),
+            )),
+        ];
+
+        for l in &limiters {
+            assert!(!l.limit_n(5));
+            assert!(l.limit());
+            l.undo();
+            assert!(!l.limit());
+        }
+    }
+
+    #[test]
+    fn should_report_log_decisions() {
+        let clock = MockClock::new();
+        let l = SlidingWindowLog::with_clock(3, Duration::seconds(60), clock.clone());
+
+        l.check();
+        clock.advance(StdDuration::from_secs(20));
+        let d = l.check_n(2);
+        assert!(d.allowed);
+        assert_eq!(d.remaining, 0);
+        assert_eq!(d.retry_after, StdDuration::from_secs(60));
+        assert_eq!(d.reset_after, StdDuration::from_secs(60));
+
+        let d = l.check();
+        assert!(d.is_limited());
+        assert_eq!(d.retry_after, StdDuration::from_secs(40));
+    }
+
+    #[test]
+    fn should_report_counter_decisions() {
+        let clock = MockClock::new();
+        let l = SlidingWindowCounter::with_clock(10, Duration::seconds(60), clock.clone());
+
+        assert!(l.check_n(10).allowed);
+        let d = l.check_n(5);
+        assert!(d.is_limited());
+        assert_eq!(d.remaining, 0);
+        assert_eq!(d.retry_after, StdDuration::from_secs(90));
+        assert_eq!(d.reset_after, StdDuration::from_secs(120));
+
+        clock.advance(StdDuration::from_secs(90));
+        assert!(l.check_n(5).allowed);
+        assert_eq!(l.snapshot().remaining, 0);
+
+        l.update_rate(20).unwrap();
+        assert_eq!(l.snapshot().capacity, 20);
+        assert!(!l.limit_n(10));
+    }
+
+    #[test]
+    fn should_survive_counter_clock_going_backwards() {
+        let clock = MockClock::new();
+        clock.set(StdDuration::from_secs(10));
+        let l = SlidingWindowCounter::with_clock(10, Duration::seconds(60), clock.clone());
+        assert!(!l.limit_n(4));
+
+        clock.set(StdDuration::from_secs(9));
+        let d = l.check_n(6);
+        assert!(d.allowed);
+        assert_eq!(d.remaining, 0);
+        assert!(l.limit());
+        assert_eq!(l.snapshot().remaining, 0);
+
+        clock.set(StdDuration::from_secs(130));
+        assert!(!l.limit_n(10));
+    }
+
+    #[test]
+    fn should_report_invalid_windows() {
+        assert_eq!(
+            SlidingWindowLog::try_new(1, Duration::days(365 * 300)).err(),
+            Some(LimiterError::Overflow)
+        );
+        assert_eq!(
+            SlidingWindowCounter::try_new(1, Duration::days(365 * 300)).err(),
+            Some(LimiterError::Overflow)
+        );
+        assert_eq!(
+            SlidingWindowLog::try_new(0, Duration::seconds(1)).err(),
+            Some(LimiterError::ZeroRate)
+        );
+        assert_eq!(
+            SlidingWindowCounter::try_new(1, Duration::seconds(-1)).err(),
+            Some(LimiterError::InvalidPeriod)
+        );
+
+        let l = SlidingWindowCounter::with_clock(0, Duration::zero(), MockClock::new());
+        assert_eq!(l.snapshot().capacity, 1);
+        assert_eq!(l.snapshot().period, StdDuration::from_secs(1));
+    }
+
+    #[test]
+    fn should_handle_extreme_limits() {
+        let l = SlidingWindowLog::with_clock(i64::MAX, Duration::seconds(1), MockClock::new());
+        assert!(!l.limit_n(3));
+        assert!(l.limit_n(u64::MAX));
+
+        let c = SlidingWindowCounter::with_clock(i64::MAX, Duration::seconds(1), MockClock::new());
+        assert!(!c.limit_n(i64::MAX as u64));
+        assert!(c.limit_n(u64::MAX));
+    }
+}